@@ -0,0 +1,16 @@
+-- Cars whose sticker came back broken or empty are kept as placeholders until a recheck succeeds
+ALTER TABLE cars ADD COLUMN status TEXT NOT NULL DEFAULT 'ok';
+
+CREATE TABLE IF NOT EXISTS broken_stickers (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    serial_number INTEGER NOT NULL UNIQUE,
+    vin TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    raw_body TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 1,
+    first_seen TEXT NOT NULL,
+    last_checked TEXT NOT NULL,
+    resolved_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS broken_stickers_unresolved ON broken_stickers (resolved_at, last_checked);
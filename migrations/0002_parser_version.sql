@@ -0,0 +1,2 @@
+-- Rows written before the parser was versioned are treated as version 1
+ALTER TABLE cars ADD COLUMN parser_version INTEGER NOT NULL DEFAULT 1;
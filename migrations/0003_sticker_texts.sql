@@ -0,0 +1,6 @@
+-- Text pulled out of each archived sticker pdf, so parsing never has to extract it twice
+CREATE TABLE IF NOT EXISTS sticker_texts (
+    vin TEXT PRIMARY KEY,
+    text TEXT NOT NULL,
+    extracted_at TEXT NOT NULL
+);
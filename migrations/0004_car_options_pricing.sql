@@ -0,0 +1,26 @@
+-- Option packages and prices read off the window sticker, one set per car
+CREATE TABLE IF NOT EXISTS car_options (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    car_id INTEGER NOT NULL REFERENCES cars (id),
+    package_code TEXT NOT NULL,
+    description TEXT NOT NULL,
+    price INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS car_options_car_id ON car_options (car_id);
+CREATE INDEX IF NOT EXISTS car_options_package_code ON car_options (package_code);
+
+CREATE TABLE IF NOT EXISTS car_pricing (
+    car_id INTEGER PRIMARY KEY REFERENCES cars (id),
+    base_msrp INTEGER,
+    destination INTEGER,
+    total_msrp INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS car_pricing_total_msrp ON car_pricing (total_msrp);
+
+-- Hand maintained decoding of the OPT.CODE printed next to the model code
+CREATE TABLE IF NOT EXISTS option_codes (
+    opt_code TEXT PRIMARY KEY,
+    description TEXT NOT NULL
+);
@@ -0,0 +1,9 @@
+-- Trim catalog keyed by the model code printed as MODEL/OPT.CODE on the sticker
+CREATE TABLE IF NOT EXISTS car_models (
+    model_code TEXT PRIMARY KEY,
+    description TEXT NOT NULL
+);
+
+ALTER TABLE cars ADD COLUMN model_code TEXT REFERENCES car_models (model_code);
+
+CREATE INDEX IF NOT EXISTS cars_model_code ON cars (model_code);
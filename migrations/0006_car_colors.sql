@@ -0,0 +1,5 @@
+-- Normalized colors sit next to the raw sticker text in ext_color / int_color
+ALTER TABLE cars ADD COLUMN ext_color_name TEXT;
+ALTER TABLE cars ADD COLUMN ext_paint_code TEXT;
+ALTER TABLE cars ADD COLUMN int_color_name TEXT;
+ALTER TABLE cars ADD COLUMN int_material TEXT;
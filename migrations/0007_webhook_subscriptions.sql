@@ -0,0 +1,31 @@
+CREATE TABLE IF NOT EXISTS subscriptions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    filter TEXT NOT NULL,
+    active INTEGER NOT NULL DEFAULT 1,
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    subscription_id INTEGER NOT NULL REFERENCES subscriptions (id),
+    car_id INTEGER NOT NULL REFERENCES cars (id),
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    response_status INTEGER,
+    last_error TEXT,
+    created_at TEXT NOT NULL,
+    next_attempt_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_retry ON webhook_deliveries (status, next_attempt_at);
+
+-- Zip centroids for radius filters; load from the census ZCTA gazetteer, it's too big to ship here
+CREATE TABLE IF NOT EXISTS zip_codes (
+    zip TEXT PRIMARY KEY,
+    latitude REAL NOT NULL,
+    longitude REAL NOT NULL
+);
@@ -0,0 +1,15 @@
+-- Owners watching for a specific car; the serial is derived from the VIN when only that is known
+CREATE TABLE IF NOT EXISTS watches (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    serial_number INTEGER NOT NULL,
+    vin TEXT,
+    webhook_url TEXT,
+    email TEXT,
+    secret TEXT NOT NULL,
+    found_car_id INTEGER REFERENCES cars (id),
+    last_checked_at TEXT,
+    last_notified_at TEXT,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS watches_serial_number ON watches (serial_number);
@@ -0,0 +1,13 @@
+-- One row per field that changed when a stored car was scraped or parsed again
+CREATE TABLE IF NOT EXISTS car_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    car_id INTEGER NOT NULL REFERENCES cars (id),
+    serial_number INTEGER NOT NULL,
+    field TEXT NOT NULL,
+    old_value TEXT,
+    new_value TEXT,
+    source TEXT NOT NULL,
+    changed_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS car_history_car_id ON car_history (car_id, changed_at);
@@ -0,0 +1,2 @@
+-- Row version, bumped on every update; the copy cached in KV is stale when its version is lower
+ALTER TABLE cars ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
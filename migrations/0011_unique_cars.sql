@@ -0,0 +1,50 @@
+-- Concurrent scrapes could insert the same car twice before serial_number was unique.
+-- Keep the first row for each serial so the indexes can be built. History, watches and webhook
+-- deliveries move over to the row that's kept; the kept row has its own options and pricing, so
+-- the duplicate's are dropped.
+UPDATE car_history
+SET car_id = (
+    SELECT MIN(kept.id) FROM cars AS kept
+    WHERE kept.serial_number = (SELECT serial_number FROM cars WHERE id = car_history.car_id)
+)
+WHERE car_id NOT IN (SELECT MIN(id) FROM cars GROUP BY serial_number);
+UPDATE watches
+SET found_car_id = (
+    SELECT MIN(kept.id) FROM cars AS kept
+    WHERE kept.serial_number = (SELECT serial_number FROM cars WHERE id = watches.found_car_id)
+)
+WHERE found_car_id NOT IN (SELECT MIN(id) FROM cars GROUP BY serial_number);
+UPDATE webhook_deliveries
+SET car_id = (
+    SELECT MIN(kept.id) FROM cars AS kept
+    WHERE kept.serial_number = (SELECT serial_number FROM cars WHERE id = webhook_deliveries.car_id)
+)
+WHERE car_id NOT IN (SELECT MIN(id) FROM cars GROUP BY serial_number);
+DELETE FROM car_options WHERE car_id NOT IN (SELECT MIN(id) FROM cars GROUP BY serial_number);
+DELETE FROM car_pricing WHERE car_id NOT IN (SELECT MIN(id) FROM cars GROUP BY serial_number);
+DELETE FROM cars WHERE id NOT IN (SELECT MIN(id) FROM cars GROUP BY serial_number);
+
+UPDATE car_history
+SET car_id = (
+    SELECT MIN(kept.id) FROM cars AS kept
+    WHERE kept.vin = (SELECT vin FROM cars WHERE id = car_history.car_id)
+)
+WHERE car_id NOT IN (SELECT MIN(id) FROM cars GROUP BY vin);
+UPDATE watches
+SET found_car_id = (
+    SELECT MIN(kept.id) FROM cars AS kept
+    WHERE kept.vin = (SELECT vin FROM cars WHERE id = watches.found_car_id)
+)
+WHERE found_car_id NOT IN (SELECT MIN(id) FROM cars GROUP BY vin);
+UPDATE webhook_deliveries
+SET car_id = (
+    SELECT MIN(kept.id) FROM cars AS kept
+    WHERE kept.vin = (SELECT vin FROM cars WHERE id = webhook_deliveries.car_id)
+)
+WHERE car_id NOT IN (SELECT MIN(id) FROM cars GROUP BY vin);
+DELETE FROM car_options WHERE car_id NOT IN (SELECT MIN(id) FROM cars GROUP BY vin);
+DELETE FROM car_pricing WHERE car_id NOT IN (SELECT MIN(id) FROM cars GROUP BY vin);
+DELETE FROM cars WHERE id NOT IN (SELECT MIN(id) FROM cars GROUP BY vin);
+
+CREATE UNIQUE INDEX IF NOT EXISTS cars_serial_number_unique ON cars (serial_number);
+CREATE UNIQUE INDEX IF NOT EXISTS cars_vin_unique ON cars (vin);
@@ -0,0 +1,73 @@
+-- One box search over cars and dealers. The trigram tokenizer matches any fragment of three or
+-- more characters, so the tail of a VIN or part of a dealer code finds its row.
+-- Cars are indexed under their own id as rowid, dealers under the negated dealer id.
+CREATE VIRTUAL TABLE IF NOT EXISTS search_index USING fts5 (
+    kind UNINDEXED,
+    ref UNINDEXED,
+    identifier,
+    dealer,
+    colors,
+    description,
+    tokenize = 'trigram'
+);
+
+INSERT INTO search_index (rowid, kind, ref, identifier, dealer, colors, description)
+SELECT id, 'car', id,
+    vin || ' ' || serial_number,
+    sold_to || ' ' || ship_to,
+    ext_color || ' ' || COALESCE(ext_color_name, '') || ' ' || COALESCE(ext_paint_code, '') || ' ' || int_color || ' ' || COALESCE(int_color_name, '') || ' ' || COALESCE(int_material, ''),
+    car_model || ' ' || COALESCE(model_code, '') || ' ' || opt_code || ' ' || model_year
+FROM cars WHERE status = 'ok';
+
+INSERT INTO search_index (rowid, kind, ref, identifier, dealer, colors, description)
+SELECT -id, 'dealer', dealer_code, dealer_code, address || ' ' || zip, '', ''
+FROM dealers;
+
+-- Placeholder rows for broken stickers stay out of the index until they're recovered
+CREATE TRIGGER IF NOT EXISTS cars_search_insert AFTER INSERT ON cars WHEN NEW.status = 'ok'
+BEGIN
+    INSERT INTO search_index (rowid, kind, ref, identifier, dealer, colors, description)
+    VALUES (
+        NEW.id, 'car', NEW.id,
+        NEW.vin || ' ' || NEW.serial_number,
+        NEW.sold_to || ' ' || NEW.ship_to,
+        NEW.ext_color || ' ' || COALESCE(NEW.ext_color_name, '') || ' ' || COALESCE(NEW.ext_paint_code, '') || ' ' || NEW.int_color || ' ' || COALESCE(NEW.int_color_name, '') || ' ' || COALESCE(NEW.int_material, ''),
+        NEW.car_model || ' ' || COALESCE(NEW.model_code, '') || ' ' || NEW.opt_code || ' ' || NEW.model_year
+    );
+END;
+
+CREATE TRIGGER IF NOT EXISTS cars_search_update AFTER UPDATE ON cars
+BEGIN
+    DELETE FROM search_index WHERE rowid = OLD.id;
+    INSERT INTO search_index (rowid, kind, ref, identifier, dealer, colors, description)
+    SELECT
+        NEW.id, 'car', NEW.id,
+        NEW.vin || ' ' || NEW.serial_number,
+        NEW.sold_to || ' ' || NEW.ship_to,
+        NEW.ext_color || ' ' || COALESCE(NEW.ext_color_name, '') || ' ' || COALESCE(NEW.ext_paint_code, '') || ' ' || NEW.int_color || ' ' || COALESCE(NEW.int_color_name, '') || ' ' || COALESCE(NEW.int_material, ''),
+        NEW.car_model || ' ' || COALESCE(NEW.model_code, '') || ' ' || NEW.opt_code || ' ' || NEW.model_year
+    WHERE NEW.status = 'ok';
+END;
+
+CREATE TRIGGER IF NOT EXISTS cars_search_delete AFTER DELETE ON cars
+BEGIN
+    DELETE FROM search_index WHERE rowid = OLD.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS dealers_search_insert AFTER INSERT ON dealers
+BEGIN
+    INSERT INTO search_index (rowid, kind, ref, identifier, dealer, colors, description)
+    VALUES (-NEW.id, 'dealer', NEW.dealer_code, NEW.dealer_code, NEW.address || ' ' || NEW.zip, '', '');
+END;
+
+CREATE TRIGGER IF NOT EXISTS dealers_search_update AFTER UPDATE ON dealers
+BEGIN
+    DELETE FROM search_index WHERE rowid = -OLD.id;
+    INSERT INTO search_index (rowid, kind, ref, identifier, dealer, colors, description)
+    VALUES (-NEW.id, 'dealer', NEW.dealer_code, NEW.dealer_code, NEW.address || ' ' || NEW.zip, '', '');
+END;
+
+CREATE TRIGGER IF NOT EXISTS dealers_search_delete AFTER DELETE ON dealers
+BEGIN
+    DELETE FROM search_index WHERE rowid = -OLD.id;
+END;
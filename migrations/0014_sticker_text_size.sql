@@ -0,0 +1,3 @@
+-- Which pdf each cached text was extracted from, so a replaced sticker isn't read through stale
+-- text. Rows from before this have no size and get extracted again on their next read.
+ALTER TABLE sticker_texts ADD COLUMN pdf_size INTEGER;
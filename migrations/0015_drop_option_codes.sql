@@ -0,0 +1,2 @@
+-- Nothing ever filled the OPT.CODE decoding table, so every lookup came back empty
+DROP TABLE IF EXISTS option_codes;
@@ -0,0 +1,2 @@
+-- Never filled, radius filters on subscriptions are refused instead
+DROP TABLE IF EXISTS zip_codes;
@@ -0,0 +1,394 @@
+use std::str::FromStr;
+
+use chrono::Utc;
+use derive_more::Display;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::*;
+
+const KEY_PREFIX: &str = "fc_";
+const KEY_LENGTH: usize = 40;
+// Enough of the key to tell keys apart in a listing without making the hash guessable
+const VISIBLE_PREFIX_LENGTH: usize = 8;
+
+/// Scopes are ordered, each one includes everything below it.
+#[derive(
+    Debug,
+    Display,
+    Deserialize,
+    Serialize,
+    JsonSchema,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    #[display(fmt = "read")]
+    Read,
+    #[display(fmt = "scrape")]
+    Scrape,
+    #[display(fmt = "admin")]
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "read" => Ok(Scope::Read),
+            "scrape" => Ok(Scope::Scrape),
+            "admin" => Ok(Scope::Admin),
+            other => Err(format!("Unknown scope: {other}").into()),
+        }
+    }
+}
+
+/// The scope a request needs, or `None` for routes that stay public.
+pub fn required_scope(method: &Method, path: &str) -> Option<Scope> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        (Method::Options, _) => None,
+        // Delivery logs carry every subscriber's url and payloads, not just the caller's
+        (_, ["admin", ..]) | (_, ["keys", ..]) | (_, ["subscriptions", _, "deliveries"]) => {
+            Some(Scope::Admin)
+        }
+        (_, ["scrape_next", ..])
+        | (_, ["scrape_below", ..])
+        | (_, ["scrape_above", ..])
+        | (_, ["scrape", ..])
+        | (_, ["vinlookup", ..])
+        | (Method::Post, ["serial", _])
+        | (Method::Post, ["car", _, "rescrape"]) => Some(Scope::Scrape),
+        (_, ["subscriptions", ..]) => Some(Scope::Read),
+        _ => None,
+    }
+}
+
+/// The key from `Authorization: Bearer ...`, or `X-Api-Key` for clients that can't set that.
+pub fn presented_key(req: &Request) -> Option<String> {
+    let headers = req.headers();
+    if let Ok(Some(authorization)) = headers.get("Authorization") {
+        if let Some(key) = authorization.strip_prefix("Bearer ") {
+            return Some(key.trim().to_string());
+        }
+    }
+    headers.get("X-Api-Key").ok().flatten()
+}
+
+/// Who a request is, once `authorize` has let it through or turned it away.
+pub enum Access {
+    /// A public route, no key checked.
+    Anonymous,
+    /// A checked key, by id, or `None` for the `ADMIN_API_KEY` secret.
+    Key(Option<i32>),
+    Denied(Response),
+}
+
+impl Access {
+    /// Identifies the client for rate limiting: checked keys by key, everyone else by ip.
+    pub fn client_key(&self, req: &Request) -> String {
+        match self {
+            Access::Key(Some(id)) => format!("key:{id}"),
+            Access::Key(None) => "key:admin".to_string(),
+            _ => {
+                let ip = req.headers().get("CF-Connecting-IP").ok().flatten();
+                format!("ip:{}", ip.unwrap_or_else(|| "unknown".to_string()))
+            }
+        }
+    }
+}
+
+/// Runs ahead of the router: turns the request away when its route needs an API key and it
+/// doesn't carry one with the right scope, and counts the request against the key.
+pub async fn authorize(req: &Request, env: &Env) -> Result<Access> {
+    let scope = match required_scope(&req.method(), &req.path()) {
+        Some(scope) => scope,
+        None => return Ok(Access::Anonymous),
+    };
+    let key = match presented_key(req) {
+        Some(key) => key,
+        None => return Ok(Access::Denied(Response::error("API key required", 401)?)),
+    };
+    // The ADMIN_API_KEY secret bootstraps key management, it has every scope and isn't counted
+    if let Ok(admin_key) = env.secret("ADMIN_API_KEY") {
+        if hash_key(&admin_key.to_string()) == hash_key(&key) {
+            return Ok(Access::Key(None));
+        }
+    }
+    let repo = ApiKeyRepository::new(env.d1("failcat_db")?);
+    let api_key = match repo.find(&key).await? {
+        Some(api_key) => api_key,
+        None => return Ok(Access::Denied(Response::error("Invalid API key", 401)?)),
+    };
+    if !api_key.allows(scope) {
+        let message = format!("API key lacks the {scope} scope");
+        return Ok(Access::Denied(Response::error(message, 403)?));
+    }
+    repo.record_usage(api_key.id).await?;
+    Ok(Access::Key(Some(api_key.id)))
+}
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ApiKeyRow {
+    id: i32,
+    name: String,
+    key_prefix: String,
+    scopes: String,
+    created_at: String,
+    last_used_at: Option<String>,
+    revoked_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    pub key_prefix: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    pub revoked_at: Option<String>,
+}
+
+impl TryFrom<ApiKeyRow> for ApiKey {
+    type Error = Error;
+
+    fn try_from(row: ApiKeyRow) -> Result<Self> {
+        Ok(ApiKey {
+            id: row.id,
+            name: row.name,
+            key_prefix: row.key_prefix,
+            scopes: row
+                .scopes
+                .split(',')
+                .filter(|scope| !scope.is_empty())
+                .map(Scope::from_str)
+                .collect::<Result<_>>()?,
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+            revoked_at: row.revoked_at,
+        })
+    }
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|granted| *granted >= scope)
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    /// The only time the plain key is shown.
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct KeyUsage {
+    pub day: String,
+    pub requests: i32,
+}
+
+pub struct ApiKeyRepository {
+    d1: Database,
+}
+
+impl ApiKeyRepository {
+    pub fn new(d1: Database) -> Self {
+        ApiKeyRepository { d1 }
+    }
+
+    pub async fn create(&self, new: NewApiKey) -> Result<CreatedApiKey> {
+        let random: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(KEY_LENGTH)
+            .map(char::from)
+            .collect();
+        let key = format!("{KEY_PREFIX}{random}");
+        let scopes: Vec<String> = new.scopes.iter().map(Scope::to_string).collect();
+        let statement = self.d1.prepare(
+            "INSERT INTO api_keys (name, key_prefix, key_hash, scopes, created_at) VALUES (?, ?, ?, ?, ?) RETURNING *",
+        );
+        let query = statement.bind(&[
+            new.name.into(),
+            key[..VISIBLE_PREFIX_LENGTH].into(),
+            hash_key(&key).into(),
+            scopes.join(",").into(),
+            Utc::now().to_rfc3339().into(),
+        ])?;
+        match query.first::<ApiKeyRow>(None).await? {
+            Some(row) => Ok(CreatedApiKey {
+                api_key: row.try_into()?,
+                key,
+            }),
+            None => Err("No api key created".into()),
+        }
+    }
+
+    pub async fn get_all(&self) -> Result<Vec<ApiKey>> {
+        let statement = self.d1.prepare("SELECT * FROM api_keys ORDER BY id");
+        statement
+            .all()
+            .await?
+            .results::<ApiKeyRow>()?
+            .into_iter()
+            .map(ApiKey::try_from)
+            .collect()
+    }
+
+    /// The unrevoked key matching a presented plain key.
+    pub async fn find(&self, key: &str) -> Result<Option<ApiKey>> {
+        let statement = self
+            .d1
+            .prepare("SELECT * FROM api_keys WHERE key_hash = ? AND revoked_at IS NULL");
+        let query = statement.bind(&[hash_key(key).into()])?;
+        query
+            .first::<ApiKeyRow>(None)
+            .await?
+            .map(ApiKey::try_from)
+            .transpose()
+    }
+
+    pub async fn revoke(&self, id: i32) -> Result<Option<ApiKey>> {
+        let statement = self.d1.prepare(
+            "UPDATE api_keys SET revoked_at = COALESCE(revoked_at, ?) WHERE id = ? RETURNING *",
+        );
+        let query = statement.bind(&[Utc::now().to_rfc3339().into(), id.into()])?;
+        query
+            .first::<ApiKeyRow>(None)
+            .await?
+            .map(ApiKey::try_from)
+            .transpose()
+    }
+
+    pub async fn record_usage(&self, id: i32) -> Result<()> {
+        let now = Utc::now();
+        let count = self
+            .d1
+            .prepare(
+                "INSERT INTO api_key_usage (key_id, day, requests) VALUES (?, ?, 1)
+                ON CONFLICT (key_id, day) DO UPDATE SET requests = requests + 1",
+            )
+            .bind(&[id.into(), now.format("%Y-%m-%d").to_string().into()])?;
+        let touch = self
+            .d1
+            .prepare("UPDATE api_keys SET last_used_at = ? WHERE id = ?")
+            .bind(&[now.to_rfc3339().into(), id.into()])?;
+        self.d1.batch(vec![count, touch]).await?;
+        Ok(())
+    }
+
+    /// Daily request counts for a key, most recent first.
+    pub async fn usage(&self, id: i32) -> Result<Vec<KeyUsage>> {
+        let statement = self.d1.prepare(
+            "SELECT day, requests FROM api_key_usage WHERE key_id = ? ORDER BY day DESC LIMIT 90",
+        );
+        let query = statement.bind(&[id.into()])?;
+        query.all().await?.results::<KeyUsage>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_routes_need_no_scope() {
+        for path in [
+            "/cars",
+            "/car/12",
+            "/dealers",
+            "/feed.atom",
+            "/openapi.json",
+        ] {
+            assert_eq!(required_scope(&Method::Get, path), None, "{path}");
+        }
+        assert_eq!(required_scope(&Method::Get, "/serial/123"), None);
+        assert_eq!(required_scope(&Method::Options, "/admin/jobs"), None);
+    }
+
+    #[test]
+    fn scraping_needs_scrape_scope() {
+        for path in [
+            "/scrape_next",
+            "/scrape_below/5",
+            "/scrape/jobs",
+            "/vinlookup/1",
+        ] {
+            assert_eq!(
+                required_scope(&Method::Get, path),
+                Some(Scope::Scrape),
+                "{path}"
+            );
+        }
+        assert_eq!(
+            required_scope(&Method::Post, "/serial/123"),
+            Some(Scope::Scrape)
+        );
+        assert_eq!(
+            required_scope(&Method::Post, "/car/12/rescrape"),
+            Some(Scope::Scrape)
+        );
+    }
+
+    #[test]
+    fn subscriptions_need_read_scope() {
+        assert_eq!(
+            required_scope(&Method::Post, "/subscriptions"),
+            Some(Scope::Read)
+        );
+    }
+
+    #[test]
+    fn delivery_logs_need_admin_scope() {
+        assert_eq!(
+            required_scope(&Method::Get, "/subscriptions/3/deliveries"),
+            Some(Scope::Admin)
+        );
+    }
+
+    #[test]
+    fn watches_stay_public() {
+        // Anyone can watch for their car; the status hides where notifications go.
+        assert_eq!(required_scope(&Method::Post, "/watch"), None);
+        assert_eq!(required_scope(&Method::Get, "/watch/7"), None);
+    }
+
+    #[test]
+    fn key_management_needs_admin_scope() {
+        for path in ["/keys", "/keys/4/usage", "/admin/reparse"] {
+            assert_eq!(
+                required_scope(&Method::Get, path),
+                Some(Scope::Admin),
+                "{path}"
+            );
+        }
+        assert_eq!(required_scope(&Method::Post, "/keys"), Some(Scope::Admin));
+    }
+
+    #[test]
+    fn higher_scopes_include_lower_ones() {
+        assert!(Scope::Admin > Scope::Scrape);
+        assert!(Scope::Scrape > Scope::Read);
+    }
+}
@@ -1,4 +1,5 @@
 use chrono::{DateTime, Utc, TimeZone};
+use schemars::JsonSchema;
 use serde::{Deserializer, Deserialize, Serialize};
 
 use crate::models::{SerialNumber, CarId};
@@ -10,11 +11,13 @@ where
 {
     let s = String::deserialize(deserializer)?;
 
+    // D1 rows use the sql format, cars cached in KV were serialized by chrono as RFC 3339
     Utc.datetime_from_str(&s, "%Y-%m-%d %H:%M:%S%.6f")
+        .or_else(|_| DateTime::parse_from_rfc3339(&s).map(|date| date.with_timezone(&Utc)))
         .map_err(serde::de::Error::custom)
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct ScrapeResponse {
     pub attempted: SerialNumber,
     pub found: Option<CarId>
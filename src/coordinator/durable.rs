@@ -0,0 +1,164 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+use worker::*;
+
+use crate::models::SerialNumber;
+
+use super::*;
+
+// Every isolate talks to this one instance, which is what makes the cursor and leases global
+const COORDINATOR_NAME: &str = "scrape-coordinator";
+const STATE_KEY: &str = "state";
+
+#[derive(Debug, Deserialize, Serialize)]
+struct LeaseRequest {
+    worker: String,
+    floor: SerialNumber,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ClaimRequest {
+    worker: String,
+    item: WorkItem,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CompleteRequest {
+    lease_id: String,
+    outcome: WorkOutcome,
+}
+
+/// Holds the `CoordinatorState` and persists it after every change, so a restarted object
+/// picks up the cursor and in-flight leases where it left off.
+#[durable_object]
+pub struct ScrapeCoordinatorObject {
+    state: State,
+    coordinator: Option<CoordinatorState>,
+}
+
+impl ScrapeCoordinatorObject {
+    async fn load(&mut self) -> CoordinatorState {
+        match self.coordinator.take() {
+            Some(coordinator) => coordinator,
+            None => self
+                .state
+                .storage()
+                .get::<CoordinatorState>(STATE_KEY)
+                .await
+                .unwrap_or_default(),
+        }
+    }
+
+    async fn save(&mut self, coordinator: CoordinatorState) -> Result<()> {
+        self.state.storage().put(STATE_KEY, &coordinator).await?;
+        self.coordinator = Some(coordinator);
+        Ok(())
+    }
+}
+
+#[durable_object]
+impl DurableObject for ScrapeCoordinatorObject {
+    fn new(state: State, _env: Env) -> Self {
+        ScrapeCoordinatorObject {
+            state,
+            coordinator: None,
+        }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let now = Utc::now().timestamp_millis();
+        let mut coordinator = self.load().await;
+        let response = match req.path().as_str() {
+            "/enqueue" => {
+                coordinator.enqueue(req.json().await?);
+                Response::empty()
+            }
+            "/lease" => {
+                let lease: LeaseRequest = req.json().await?;
+                Response::from_json(&coordinator.lease(&lease.worker, lease.floor, now))
+            }
+            "/claim" => {
+                let claim: ClaimRequest = req.json().await?;
+                Response::from_json(&coordinator.claim(&claim.worker, claim.item, now))
+            }
+            "/complete" => {
+                let complete: CompleteRequest = req.json().await?;
+                coordinator.complete(&complete.lease_id, complete.outcome, now);
+                Response::empty()
+            }
+            "/status" => Response::from_json(&coordinator.status()),
+            _ => Response::error("Not Found", 404),
+        };
+        self.save(coordinator).await?;
+        response
+    }
+}
+
+/// Talks to the `ScrapeCoordinatorObject` behind the `SCRAPE_COORDINATOR` binding.
+pub struct DurableCoordinator {
+    stub: Stub,
+}
+
+impl DurableCoordinator {
+    pub fn new(env: &Env) -> Result<Self> {
+        let stub = env
+            .durable_object("SCRAPE_COORDINATOR")?
+            .id_from_name(COORDINATOR_NAME)?
+            .get_stub()?;
+        Ok(DurableCoordinator { stub })
+    }
+
+    async fn post<B: Serialize>(&self, path: &str, body: &B) -> Result<Response> {
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post)
+            .with_body(Some(serde_json::to_string(body)?.into()));
+        let request = Request::new_with_init(&format!("https://coordinator{path}"), &init)?;
+        let response = self.stub.fetch_with_request(request).await?;
+        match response.status_code() {
+            200..=299 => Ok(response),
+            status => Err(format!("coordinator {path} answered {status}").into()),
+        }
+    }
+
+    async fn call<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T> {
+        self.post(path, body).await?.json().await
+    }
+}
+
+#[async_trait(?Send)]
+impl ScrapeCoordinator for DurableCoordinator {
+    async fn enqueue(&self, items: Vec<WorkItem>) -> Result<()> {
+        self.post("/enqueue", &items).await?;
+        Ok(())
+    }
+
+    async fn lease(&self, worker: &str, floor: SerialNumber) -> Result<Option<Lease>> {
+        let request = LeaseRequest {
+            worker: worker.to_string(),
+            floor,
+        };
+        self.call("/lease", &request).await
+    }
+
+    async fn claim(&self, worker: &str, item: WorkItem) -> Result<Option<Lease>> {
+        let request = ClaimRequest {
+            worker: worker.to_string(),
+            item,
+        };
+        self.call("/claim", &request).await
+    }
+
+    async fn complete(&self, lease: &Lease, outcome: WorkOutcome) -> Result<()> {
+        let request = CompleteRequest {
+            lease_id: lease.id.clone(),
+            outcome,
+        };
+        self.post("/complete", &request).await?;
+        Ok(())
+    }
+
+    async fn status(&self) -> Result<CoordinatorStatus> {
+        self.call("/status", &()).await
+    }
+}
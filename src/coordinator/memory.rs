@@ -0,0 +1,184 @@
+use std::cell::RefCell;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use worker::*;
+
+use crate::models::SerialNumber;
+
+use super::*;
+
+/// The coordinator in a single process, for tests. The clock can be swapped to step through
+/// lease expiry and upstream pauses.
+pub struct InMemoryCoordinator {
+    state: RefCell<CoordinatorState>,
+    clock: fn() -> i64,
+}
+
+impl Default for InMemoryCoordinator {
+    fn default() -> Self {
+        Self::with_clock(|| Utc::now().timestamp_millis())
+    }
+}
+
+impl InMemoryCoordinator {
+    pub fn with_clock(clock: fn() -> i64) -> Self {
+        InMemoryCoordinator {
+            state: RefCell::new(CoordinatorState::default()),
+            clock,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl ScrapeCoordinator for InMemoryCoordinator {
+    async fn enqueue(&self, items: Vec<WorkItem>) -> Result<()> {
+        self.state.borrow_mut().enqueue(items);
+        Ok(())
+    }
+
+    async fn lease(&self, worker: &str, floor: SerialNumber) -> Result<Option<Lease>> {
+        Ok(self.state.borrow_mut().lease(worker, floor, (self.clock)()))
+    }
+
+    async fn claim(&self, worker: &str, item: WorkItem) -> Result<Option<Lease>> {
+        Ok(self.state.borrow_mut().claim(worker, item, (self.clock)()))
+    }
+
+    async fn complete(&self, lease: &Lease, outcome: WorkOutcome) -> Result<()> {
+        self.state
+            .borrow_mut()
+            .complete(&lease.id, outcome, (self.clock)());
+        Ok(())
+    }
+
+    async fn status(&self) -> Result<CoordinatorStatus> {
+        Ok(self.state.borrow().status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    fn serial(n: i32) -> SerialNumber {
+        SerialNumber(n)
+    }
+
+    #[test]
+    fn lease_hands_out_queued_work_by_kind_then_the_cursor() {
+        let mut state = CoordinatorState::default();
+        state.enqueue(vec![
+            WorkItem::new(serial(10), WorkKind::Backfill),
+            WorkItem::new(serial(20), WorkKind::Watched),
+            WorkItem::new(serial(30), WorkKind::Recheck),
+        ]);
+
+        let leased: Vec<(SerialNumber, WorkKind)> = (0..4)
+            .map(|_| state.lease("w", serial(100), 0).unwrap().item)
+            .map(|item| (item.serial_number, item.kind))
+            .collect();
+        assert_eq!(
+            leased,
+            vec![
+                (serial(20), WorkKind::Watched),
+                (serial(30), WorkKind::Recheck),
+                (serial(10), WorkKind::Backfill),
+                (serial(100), WorkKind::Forward),
+            ]
+        );
+    }
+
+    #[test]
+    fn expired_leases_are_requeued() {
+        let mut state = CoordinatorState::default();
+        state.enqueue(vec![WorkItem::new(serial(5), WorkKind::Recheck)]);
+        let first = state.lease("a", serial(100), 0).unwrap();
+        assert_eq!(first.item.serial_number, serial(5));
+
+        // Still leased, so the next worker gets the cursor instead
+        let second = state.lease("b", serial(100), LEASE_TTL_MS - 1).unwrap();
+        assert_eq!(second.item.kind, WorkKind::Forward);
+
+        let retried = state.lease("c", serial(100), LEASE_TTL_MS).unwrap();
+        assert_eq!(retried.item.serial_number, serial(5));
+        assert_eq!(retried.item.attempts, 1);
+
+        // Completing the expired lease is ignored
+        state.complete(&first.id, WorkOutcome::Found, LEASE_TTL_MS);
+        assert!(state.status().in_flight.iter().any(|l| l.id == retried.id));
+    }
+
+    #[test]
+    fn claim_refuses_serials_in_flight() {
+        let mut state = CoordinatorState::default();
+        state.enqueue(vec![
+            WorkItem::new(serial(5), WorkKind::Recheck),
+            WorkItem::new(serial(6), WorkKind::Backfill),
+        ]);
+        state.lease("a", serial(100), 0).unwrap();
+
+        assert!(state
+            .claim("b", WorkItem::new(serial(5), WorkKind::Watched), 0)
+            .is_none());
+        let claimed = state
+            .claim("b", WorkItem::new(serial(6), WorkKind::Watched), 0)
+            .unwrap();
+        assert_eq!(claimed.item.kind, WorkKind::Watched);
+        assert!(state.status().queued.is_empty());
+    }
+
+    #[test]
+    fn hitting_limits_pauses_every_lease() {
+        let mut state = CoordinatorState::default();
+        state.enqueue(vec![WorkItem::new(serial(5), WorkKind::Recheck)]);
+        let lease = state.lease("a", serial(100), 0).unwrap();
+        state.complete(&lease.id, WorkOutcome::LimitsExceeded, 0);
+
+        assert_eq!(state.status().paused_until, Some(LIMITS_PAUSE_MS));
+        assert!(state.lease("a", serial(100), LIMITS_PAUSE_MS - 1).is_none());
+
+        let retried = state.lease("a", serial(100), LIMITS_PAUSE_MS).unwrap();
+        assert_eq!(retried.item.serial_number, serial(5));
+        assert_eq!(retried.item.attempts, 0);
+    }
+
+    #[test]
+    fn forward_not_found_pulls_the_cursor_back() {
+        let mut state = CoordinatorState::default();
+        let first = state.lease("a", serial(100), 0).unwrap();
+        let second = state.lease("b", serial(100), 0).unwrap();
+        assert_eq!(first.item.serial_number, serial(100));
+        assert_eq!(second.item.serial_number, serial(101));
+        assert_eq!(state.status().cursor, Some(serial(102)));
+
+        state.complete(&first.id, WorkOutcome::NotFound, 0);
+        assert_eq!(state.status().cursor, Some(serial(100)));
+
+        let again = state.lease("a", serial(100), 0).unwrap();
+        assert_eq!(again.item.serial_number, serial(100));
+        // 101 is still in flight, so it's skipped
+        let next = state.lease("a", serial(100), 0).unwrap();
+        assert_eq!(next.item.serial_number, serial(102));
+    }
+
+    #[test]
+    fn in_memory_coordinator_hands_out_the_same_leases() {
+        let coordinator = InMemoryCoordinator::with_clock(|| 0);
+        block_on(coordinator.enqueue(vec![WorkItem::new(serial(5), WorkKind::Recheck)])).unwrap();
+
+        let lease = block_on(coordinator.lease("a", serial(100)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(lease.item.serial_number, serial(5));
+        let claimed = block_on(coordinator.claim("b", lease.item)).unwrap();
+        assert!(claimed.is_none());
+
+        block_on(coordinator.complete(&lease, WorkOutcome::Found)).unwrap();
+        let status = block_on(coordinator.status()).unwrap();
+        assert!(status.queued.is_empty());
+        assert!(status.in_flight.is_empty());
+    }
+}
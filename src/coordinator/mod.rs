@@ -0,0 +1,256 @@
+use std::collections::VecDeque;
+
+use async_trait::async_trait;
+use derive_more::Display;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::models::SerialNumber;
+
+pub mod durable;
+#[cfg(test)]
+pub mod memory;
+
+pub use durable::{DurableCoordinator, ScrapeCoordinatorObject};
+#[cfg(test)]
+pub use memory::InMemoryCoordinator;
+
+// A worker that hasn't reported back by then is assumed dead and its serial goes back in the queue
+const LEASE_TTL_MS: i64 = 5 * 60 * 1000;
+// Upstream limits reset slowly, so nobody gets a lease for a while after hitting them
+const LIMITS_PAUSE_MS: i64 = 15 * 60 * 1000;
+const MAX_ATTEMPTS: i32 = 3;
+
+/// Kinds of work, in the order they're handed out.
+#[derive(
+    Debug,
+    Display,
+    Deserialize,
+    Serialize,
+    JsonSchema,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkKind {
+    #[display(fmt = "watched")]
+    Watched,
+    #[display(fmt = "recheck")]
+    Recheck,
+    #[display(fmt = "backfill")]
+    Backfill,
+    #[display(fmt = "forward")]
+    Forward,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+pub struct WorkItem {
+    pub serial_number: SerialNumber,
+    pub kind: WorkKind,
+    #[serde(default)]
+    pub attempts: i32,
+}
+
+impl WorkItem {
+    pub fn new(serial_number: SerialNumber, kind: WorkKind) -> Self {
+        WorkItem {
+            serial_number,
+            kind,
+            attempts: 0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub id: String,
+    pub worker: String,
+    pub item: WorkItem,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkOutcome {
+    Found,
+    NotFound,
+    Failed,
+    LimitsExceeded,
+    /// Failed, but whoever handed the work out retries it, so the coordinator drops it.
+    Released,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct CoordinatorStatus {
+    pub cursor: Option<SerialNumber>,
+    pub queued: Vec<WorkItem>,
+    pub in_flight: Vec<Lease>,
+    pub paused_until: Option<i64>,
+}
+
+/// Everything the scrapers need from a coordinator. The Durable Object one is shared by every
+/// isolate; the in-memory one has the same behaviour for tests.
+#[async_trait(?Send)]
+pub trait ScrapeCoordinator {
+    /// Queues serials that aren't already queued or leased.
+    async fn enqueue(&self, items: Vec<WorkItem>) -> Result<()>;
+
+    /// The next piece of work: queued serials by kind, then the forward cursor, which never
+    /// starts below `floor` (one past the highest stored serial). `None` while paused.
+    async fn lease(&self, worker: &str, floor: SerialNumber) -> Result<Option<Lease>>;
+
+    /// Leases one specific serial for a manual trigger, `None` if it's already in flight.
+    async fn claim(&self, worker: &str, item: WorkItem) -> Result<Option<Lease>>;
+
+    async fn complete(&self, lease: &Lease, outcome: WorkOutcome) -> Result<()>;
+
+    async fn status(&self) -> Result<CoordinatorStatus>;
+}
+
+/// The coordinator's bookkeeping, kept free of any storage so both implementations share it.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct CoordinatorState {
+    cursor: Option<SerialNumber>,
+    queue: VecDeque<WorkItem>,
+    in_flight: Vec<Lease>,
+    paused_until: Option<i64>,
+}
+
+fn lease_id() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect()
+}
+
+impl CoordinatorState {
+    fn is_known(&self, serial_number: SerialNumber) -> bool {
+        self.queue
+            .iter()
+            .any(|item| item.serial_number == serial_number)
+            || self
+                .in_flight
+                .iter()
+                .any(|lease| lease.item.serial_number == serial_number)
+    }
+
+    fn requeue(&mut self, mut item: WorkItem) {
+        item.attempts += 1;
+        if item.kind == WorkKind::Forward {
+            // Forward work is regenerated from the cursor, so pull the cursor back instead
+            self.cursor = self.cursor.map(|cursor| cursor.min(item.serial_number));
+        } else if item.attempts < MAX_ATTEMPTS && !self.is_known(item.serial_number) {
+            self.queue.push_front(item);
+        }
+    }
+
+    fn expire(&mut self, now: i64) {
+        let (expired, live): (Vec<Lease>, Vec<Lease>) = self
+            .in_flight
+            .drain(..)
+            .partition(|lease| lease.expires_at <= now);
+        self.in_flight = live;
+        for lease in expired {
+            self.requeue(lease.item);
+        }
+    }
+
+    fn grant(&mut self, worker: &str, item: WorkItem, now: i64) -> Lease {
+        let lease = Lease {
+            id: lease_id(),
+            worker: worker.to_string(),
+            item,
+            expires_at: now + LEASE_TTL_MS,
+        };
+        self.in_flight.push(lease.clone());
+        lease
+    }
+
+    pub fn enqueue(&mut self, items: Vec<WorkItem>) {
+        for item in items {
+            if !self.is_known(item.serial_number) {
+                self.queue.push_back(item);
+            }
+        }
+    }
+
+    pub fn lease(&mut self, worker: &str, floor: SerialNumber, now: i64) -> Option<Lease> {
+        self.expire(now);
+        if self.paused_until.map_or(false, |until| until > now) {
+            return None;
+        }
+
+        let next = self
+            .queue
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, item)| item.kind)
+            .map(|(index, _)| index);
+        if let Some(item) = next.and_then(|index| self.queue.remove(index)) {
+            return Some(self.grant(worker, item, now));
+        }
+
+        let mut cursor = self.cursor.map_or(floor, |cursor| cursor.max(floor));
+        while self.is_known(cursor) {
+            cursor = cursor + 1.into();
+        }
+        self.cursor = Some(cursor + 1.into());
+        Some(self.grant(worker, WorkItem::new(cursor, WorkKind::Forward), now))
+    }
+
+    pub fn claim(&mut self, worker: &str, item: WorkItem, now: i64) -> Option<Lease> {
+        self.expire(now);
+        if self
+            .in_flight
+            .iter()
+            .any(|lease| lease.item.serial_number == item.serial_number)
+        {
+            return None;
+        }
+        self.queue
+            .retain(|queued| queued.serial_number != item.serial_number);
+        Some(self.grant(worker, item, now))
+    }
+
+    pub fn complete(&mut self, lease_id: &str, outcome: WorkOutcome, now: i64) {
+        let index = match self.in_flight.iter().position(|lease| lease.id == lease_id) {
+            Some(index) => index,
+            // Expired and handed to someone else already
+            None => return,
+        };
+        let lease = self.in_flight.remove(index);
+        match outcome {
+            WorkOutcome::Found => {}
+            // Past the newest car upstream knows about, try it again next time round
+            WorkOutcome::NotFound if lease.item.kind == WorkKind::Forward => {
+                self.cursor = self.cursor.map(|cursor| cursor.min(lease.item.serial_number));
+            }
+            WorkOutcome::NotFound | WorkOutcome::Released => {}
+            WorkOutcome::Failed => self.requeue(lease.item),
+            WorkOutcome::LimitsExceeded => {
+                self.paused_until = Some(now + LIMITS_PAUSE_MS);
+                let mut item = lease.item;
+                // Hitting the limit isn't the serial's fault
+                item.attempts -= 1;
+                self.requeue(item);
+            }
+        }
+    }
+
+    pub fn status(&self) -> CoordinatorStatus {
+        CoordinatorStatus {
+            cursor: self.cursor,
+            queued: self.queue.iter().copied().collect(),
+            in_flight: self.in_flight.clone(),
+            paused_until: self.paused_until,
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use worker::*;
+
+const DEFAULT_ORIGINS: &str = "https://vteng.io, https://*.vteng.io, http://localhost:8787";
+const DEFAULT_METHODS: &str = "GET, POST, DELETE, OPTIONS";
+const DEFAULT_HEADERS: &str = "Content-Type, Authorization, X-Api-Key, If-None-Match";
+// Headers browsers only hand to scripts when told to
+const EXPOSED_HEADERS: &str = "Retry-After, Deprecation, Link, ETag, X-Cache";
+const DEFAULT_MAX_AGE: &str = "86400";
+
+/// An allowed origin: either the exact origin, or `*.example.com` (optionally with a scheme) for
+/// any subdomain of it. A suffix only matches on a label boundary, so `*.vteng.io` doesn't let
+/// in `evil-vteng.io`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum OriginPattern {
+    Exact(String),
+    Suffix {
+        scheme: Option<String>,
+        suffix: String,
+    },
+}
+
+impl OriginPattern {
+    fn parse(pattern: &str) -> Option<Self> {
+        let pattern = pattern.trim().trim_end_matches('/').to_lowercase();
+        if pattern.is_empty() {
+            return None;
+        }
+        let (scheme, host) = match pattern.split_once("://") {
+            Some((scheme, host)) => (Some(scheme.to_string()), host),
+            None => (None, pattern.as_str()),
+        };
+        match host.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => Some(OriginPattern::Suffix {
+                scheme,
+                suffix: suffix.to_string(),
+            }),
+            Some(_) => {
+                console_error!("ignoring CORS origin pattern {}", pattern);
+                None
+            }
+            None => Some(OriginPattern::Exact(pattern)),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Exact(exact) => exact == origin,
+            OriginPattern::Suffix { scheme, suffix } => {
+                let (origin_scheme, authority) = match origin.split_once("://") {
+                    Some(parts) => parts,
+                    None => return false,
+                };
+                let host = authority.split(':').next().unwrap_or_default();
+                scheme
+                    .as_deref()
+                    .map_or(true, |scheme| scheme == origin_scheme)
+                    && host.ends_with(suffix.as_str())
+            }
+        }
+    }
+}
+
+/// Which browser origins may call the API, configured through the `CORS_ORIGINS`,
+/// `CORS_ALLOW_METHODS`, `CORS_ALLOW_HEADERS` and `CORS_MAX_AGE` vars.
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    origins: Vec<OriginPattern>,
+    methods: String,
+    headers: String,
+    max_age: String,
+}
+
+fn var_or(env: &Env, name: &str, default: &str) -> String {
+    env.var(name)
+        .map(|value| value.to_string())
+        .unwrap_or_else(|_| default.to_string())
+}
+
+impl CorsPolicy {
+    pub fn from_env(env: &Env) -> Self {
+        CorsPolicy {
+            origins: var_or(env, "CORS_ORIGINS", DEFAULT_ORIGINS)
+                .split(',')
+                .filter_map(OriginPattern::parse)
+                .collect(),
+            methods: var_or(env, "CORS_ALLOW_METHODS", DEFAULT_METHODS),
+            headers: var_or(env, "CORS_ALLOW_HEADERS", DEFAULT_HEADERS),
+            max_age: var_or(env, "CORS_MAX_AGE", DEFAULT_MAX_AGE),
+        }
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        let normalized = origin.trim_end_matches('/').to_lowercase();
+        self.origins
+            .iter()
+            .any(|pattern| pattern.matches(&normalized))
+    }
+
+    /// The request's `Origin` when the policy allows it.
+    fn allowed_origin(&self, req: &Request) -> Option<String> {
+        let origin = req.headers().get("Origin").ok().flatten()?;
+        self.allows(&origin).then_some(origin)
+    }
+
+    /// What a preflight from `origin` gets back. A disallowed origin gets no CORS headers, which
+    /// is what makes the browser refuse the real request.
+    fn preflight_headers(&self, origin: Option<String>) -> Vec<(&'static str, String)> {
+        let mut headers = vec![("Vary", "Origin".to_string())];
+        if let Some(origin) = origin.filter(|origin| self.allows(origin)) {
+            headers.push(("Access-Control-Allow-Origin", origin));
+            headers.push(("Access-Control-Allow-Methods", self.methods.clone()));
+            headers.push(("Access-Control-Allow-Headers", self.headers.clone()));
+            headers.push(("Access-Control-Max-Age", self.max_age.clone()));
+        }
+        headers
+    }
+
+    /// Answers a preflight for any route.
+    pub fn preflight(&self, req: &Request) -> Result<Response> {
+        let origin = req.headers().get("Origin").ok().flatten();
+        let mut response = Response::empty()?.with_status(204);
+        let headers = response.headers_mut();
+        for (name, value) in self.preflight_headers(origin) {
+            headers.set(name, &value)?;
+        }
+        Ok(response)
+    }
+
+    /// Adds the CORS headers to a response, keeping the ones it already has.
+    pub fn apply(&self, req: &Request, mut response: Response) -> Result<Response> {
+        let headers = response.headers_mut();
+        headers.append("Vary", "Origin")?;
+        if let Some(origin) = self.allowed_origin(req) {
+            headers.set("Access-Control-Allow-Origin", &origin)?;
+            headers.set("Access-Control-Expose-Headers", EXPOSED_HEADERS)?;
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(origins: &str) -> CorsPolicy {
+        CorsPolicy {
+            origins: origins
+                .split(',')
+                .filter_map(OriginPattern::parse)
+                .collect(),
+            methods: DEFAULT_METHODS.to_string(),
+            headers: DEFAULT_HEADERS.to_string(),
+            max_age: DEFAULT_MAX_AGE.to_string(),
+        }
+    }
+
+    #[test]
+    fn exact_origins_match_only_themselves() {
+        let pattern = OriginPattern::parse("https://vteng.io/").unwrap();
+        assert_eq!(
+            pattern,
+            OriginPattern::Exact("https://vteng.io".to_string())
+        );
+        assert!(pattern.matches("https://vteng.io"));
+        assert!(!pattern.matches("https://www.vteng.io"));
+        assert!(!pattern.matches("https://vteng.io.attacker.com"));
+    }
+
+    #[test]
+    fn wildcards_match_subdomains() {
+        let pattern = OriginPattern::parse("https://*.vteng.io").unwrap();
+        assert!(pattern.matches("https://www.vteng.io"));
+        assert!(pattern.matches("https://a.b.vteng.io"));
+    }
+
+    #[test]
+    fn wildcards_leave_out_the_apex_unless_it_is_listed() {
+        assert!(!policy("https://*.vteng.io").allows("https://vteng.io"));
+        assert!(policy("https://vteng.io, https://*.vteng.io").allows("https://vteng.io"));
+    }
+
+    #[test]
+    fn lookalike_hosts_are_rejected() {
+        let policy = policy(DEFAULT_ORIGINS);
+        for origin in [
+            "https://evil-vteng.io",
+            "https://vteng.io.attacker.com",
+            "https://evil-vteng.io.attacker.com",
+            "https://www.vteng.io.attacker.com",
+            "vteng.io",
+        ] {
+            assert!(!policy.allows(origin), "{origin}");
+        }
+    }
+
+    #[test]
+    fn schemes_and_ports_are_checked() {
+        let defaults = policy(DEFAULT_ORIGINS);
+        assert!(!defaults.allows("http://vteng.io"));
+        assert!(!defaults.allows("http://www.vteng.io"));
+        assert!(defaults.allows("http://localhost:8787"));
+        assert!(!defaults.allows("http://localhost:3000"));
+        assert!(!defaults.allows("https://localhost:8787"));
+        // A wildcard names a host, so any port on it is let in
+        assert!(defaults.allows("https://www.vteng.io:8443"));
+
+        // Without a scheme the wildcard takes either
+        let any_scheme = policy("*.vteng.io");
+        assert!(any_scheme.allows("http://www.vteng.io"));
+        assert!(any_scheme.allows("https://www.vteng.io"));
+    }
+
+    #[test]
+    fn origins_are_compared_case_insensitively() {
+        let policy = policy(DEFAULT_ORIGINS);
+        assert!(policy.allows("HTTPS://WWW.VTENG.IO/"));
+    }
+
+    #[test]
+    fn malformed_wildcards_are_ignored() {
+        assert_eq!(OriginPattern::parse("https://*vteng.io"), None);
+        assert_eq!(OriginPattern::parse(" "), None);
+    }
+
+    #[test]
+    fn preflights_from_allowed_origins_get_the_policy() {
+        let headers =
+            policy(DEFAULT_ORIGINS).preflight_headers(Some("https://www.vteng.io".into()));
+        assert_eq!(
+            headers,
+            vec![
+                ("Vary", "Origin".to_string()),
+                (
+                    "Access-Control-Allow-Origin",
+                    "https://www.vteng.io".to_string()
+                ),
+                ("Access-Control-Allow-Methods", DEFAULT_METHODS.to_string()),
+                ("Access-Control-Allow-Headers", DEFAULT_HEADERS.to_string()),
+                ("Access-Control-Max-Age", DEFAULT_MAX_AGE.to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn preflights_from_other_origins_get_no_cors_headers() {
+        let policy = policy(DEFAULT_ORIGINS);
+        let only_vary = vec![("Vary", "Origin".to_string())];
+        assert_eq!(
+            policy.preflight_headers(Some("https://evil-vteng.io".into())),
+            only_vary
+        );
+        assert_eq!(policy.preflight_headers(None), only_vary);
+    }
+}
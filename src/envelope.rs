@@ -0,0 +1,159 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::Value;
+use worker::*;
+
+pub const V1_PREFIX: &str = "/v1";
+// Routes that describe the api rather than being part of it, left out of the deprecation
+const UNVERSIONED_PATHS: [&str; 2] = ["/", "/openapi.json"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    V1,
+    /// The original routes, kept as deprecated aliases of `/v1`.
+    Unversioned,
+}
+
+impl ApiVersion {
+    /// Strips the version prefix from a request, so both versions go through the same router,
+    /// auth and rate limits.
+    pub fn split(req: Request) -> Result<(Request, ApiVersion)> {
+        let path = req.path();
+        let rest = match path.strip_prefix(V1_PREFIX) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+            _ => return Ok((req, ApiVersion::Unversioned)),
+        };
+        let mut url = req.url()?;
+        url.set_path(if rest.is_empty() { "/" } else { rest });
+        let mut init = RequestInit::new();
+        init.with_method(req.method())
+            .with_headers(req.headers().clone())
+            .with_body(req.inner().body().map(Into::into));
+        let req = Request::new_with_init(url.as_str(), &init)?;
+        Ok((req, ApiVersion::V1))
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ApiError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Meta {
+    pub version: &'static str,
+    pub status: u16,
+    /// Set when `data` is a list.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+}
+
+/// The shape of every `/v1` JSON response: `data` on success, `error` otherwise.
+#[derive(Debug, Serialize)]
+pub struct Envelope {
+    pub data: Value,
+    pub error: Option<ApiError>,
+    pub meta: Meta,
+}
+
+fn error_code(status: u16) -> &'static str {
+    match status {
+        400 => "bad_request",
+        401 => "unauthorized",
+        403 => "forbidden",
+        404 => "not_found",
+        409 => "conflict",
+        422 => "unprocessable",
+        429 => "rate_limited",
+        502 => "upstream_error",
+        400..=499 => "client_error",
+        _ => "internal_error",
+    }
+}
+
+impl Envelope {
+    pub fn success(status: u16, data: Value) -> Self {
+        let count = data.as_array().map(Vec::len);
+        Envelope {
+            data,
+            error: None,
+            meta: Meta {
+                version: "v1",
+                status,
+                count,
+            },
+        }
+    }
+
+    /// Server side messages can carry internals, so they're logged and replaced.
+    pub fn failure(status: u16, message: String) -> Self {
+        let message = if status >= 500 {
+            console_error!("{} response: {}", status, message);
+            match status {
+                502 => "Upstream request failed".to_string(),
+                _ => "Internal server error".to_string(),
+            }
+        } else {
+            message
+        };
+        Envelope {
+            data: Value::Null,
+            error: Some(ApiError {
+                code: error_code(status),
+                message,
+            }),
+            meta: Meta {
+                version: "v1",
+                status,
+                count: None,
+            },
+        }
+    }
+}
+
+/// Wraps a route's JSON and text responses in an `Envelope`, keeping their status and headers.
+/// Files and feeds, and responses without a body, go out as they are.
+pub async fn wrap(result: Result<Response>) -> Result<Response> {
+    let mut response = match result {
+        Ok(response) => response,
+        Err(e) => {
+            let envelope = Envelope::failure(500, e.to_string());
+            return Ok(Response::from_json(&envelope)?.with_status(500));
+        }
+    };
+    let status = response.status_code();
+    let content_type = response
+        .headers()
+        .get("Content-Type")?
+        .unwrap_or_default();
+    let envelope = if status >= 400 {
+        Envelope::failure(status, response.text().await?)
+    } else if content_type.starts_with("application/json") {
+        Envelope::success(status, response.json().await?)
+    } else if content_type.starts_with("text/plain") {
+        Envelope::success(status, Value::String(response.text().await?))
+    } else {
+        return Ok(response);
+    };
+
+    let mut enveloped = Response::from_json(&envelope)?.with_status(status);
+    for (name, value) in response.headers().entries() {
+        if name != "content-type" && name != "content-length" {
+            enveloped.headers_mut().set(&name, &value)?;
+        }
+    }
+    Ok(enveloped)
+}
+
+/// Marks an unversioned response as deprecated and points at its `/v1` successor.
+pub fn deprecate(req: &Request, mut response: Response) -> Result<Response> {
+    let path = req.path();
+    if UNVERSIONED_PATHS.contains(&path.as_str()) {
+        return Ok(response);
+    }
+    let headers = response.headers_mut();
+    headers.set("Deprecation", "true")?;
+    headers.set("Link", &format!("<{V1_PREFIX}{path}>; rel=\"successor-version\""))?;
+    Ok(response)
+}
@@ -0,0 +1,78 @@
+use chrono::{DateTime, Utc};
+
+use crate::models::Car;
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn title(car: &Car) -> String {
+    format!("Serial {} - {}", car.serial_number, car.car_model)
+}
+
+fn summary(car: &Car) -> String {
+    format!(
+        "{} / {} for dealer {}, VIN {}",
+        car.ext_color_name.as_deref().unwrap_or(&car.ext_color),
+        car.int_color_name.as_deref().unwrap_or(&car.int_color),
+        car.sold_to,
+        car.vin
+    )
+}
+
+fn sticker_link(base_url: &str, car: &Car) -> String {
+    format!("{}/stickers/{}", base_url, car.vin)
+}
+
+fn updated(cars: &[Car]) -> DateTime<Utc> {
+    cars.first().map(|car| car.created_date).unwrap_or_else(Utc::now)
+}
+
+pub fn atom(cars: &[Car], self_url: &str, base_url: &str) -> String {
+    let entries: String = cars
+        .iter()
+        .map(|car| {
+            format!(
+                "<entry><title>{}</title><id>urn:failcat:car:{}</id><updated>{}</updated><link href=\"{}\"/><summary>{}</summary></entry>",
+                escape(&title(car)),
+                escape(&car.vin),
+                car.created_date.to_rfc3339(),
+                escape(&sticker_link(base_url, car)),
+                escape(&summary(car)),
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?><feed xmlns=\"http://www.w3.org/2005/Atom\"><title>failcat: new cars</title><author><name>failcat</name></author><id>{}</id><link rel=\"self\" href=\"{}\"/><updated>{}</updated>{}</feed>",
+        escape(self_url),
+        escape(self_url),
+        updated(cars).to_rfc3339(),
+        entries
+    )
+}
+
+pub fn rss(cars: &[Car], self_url: &str, base_url: &str) -> String {
+    let items: String = cars
+        .iter()
+        .map(|car| {
+            format!(
+                "<item><title>{}</title><link>{}</link><guid isPermaLink=\"false\">urn:failcat:car:{}</guid><pubDate>{}</pubDate><description>{}</description></item>",
+                escape(&title(car)),
+                escape(&sticker_link(base_url, car)),
+                escape(&car.vin),
+                car.created_date.to_rfc2822(),
+                escape(&summary(car)),
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?><rss version=\"2.0\"><channel><title>failcat: new cars</title><link>{}</link><description>Newly discovered cars</description><lastBuildDate>{}</lastBuildDate>{}</channel></rss>",
+        escape(self_url),
+        updated(cars).to_rfc2822(),
+        items
+    )
+}
@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::future::Future;
+
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::{
+    ComplexObject, Context, EmptyMutation, EmptySubscription, Enum, InputObject, Object, Schema,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use send_wrapper::SendWrapper;
+use serde::Deserialize;
+use worker::{Database, Env};
+
+use crate::models::{
+    Car, CarId, CarModel, CarModelRepository, CarOption, CarOrder, CarPricing, CarQuery,
+    CarRepository, CarStatus, Dealer, DealerRepository, OptionRepository, ScraperLog,
+    ScraperLogRepository, SerialNumber, StoredOptions,
+};
+
+// One query can't ask for more rows than this from any list
+const MAX_PAGE_SIZE: i32 = 100;
+const DEFAULT_PAGE_SIZE: i32 = 10;
+
+pub type FailcatSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+/// The body of a `POST /graphql`.
+#[derive(Debug, Deserialize)]
+pub struct GraphQLRequest {
+    pub query: String,
+    #[serde(default, rename = "operationName")]
+    pub operation_name: Option<String>,
+    #[serde(default)]
+    pub variables: Option<serde_json::Value>,
+}
+
+impl From<GraphQLRequest> for async_graphql::Request {
+    fn from(request: GraphQLRequest) -> Self {
+        let mut graphql = async_graphql::Request::new(request.query);
+        if let Some(operation_name) = request.operation_name {
+            graphql = graphql.operation_name(operation_name);
+        }
+        if let Some(variables) = request.variables {
+            graphql = graphql.variables(async_graphql::Variables::from_json(variables));
+        }
+        graphql
+    }
+}
+
+/// Built per request, so the loaders only ever cache rows for the one query.
+pub fn schema(env: Env) -> FailcatSchema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .data(loader(DealerLoader(SendWrapper::new(env.clone()))))
+        .data(loader(ModelLoader(SendWrapper::new(env.clone()))))
+        .data(loader(OptionsLoader(SendWrapper::new(env.clone()))))
+        .data(SendWrapper::new(env))
+        .limit_depth(8)
+        .limit_complexity(1000)
+        .finish()
+}
+
+/// async-graphql wants `Send` futures, which nothing touching D1 is. Workers run on one thread,
+/// so wrapping them is safe; everything holding a `Database` has to live inside `future`.
+fn resolve<T, F>(future: F) -> impl Future<Output = async_graphql::Result<T>> + Send
+where
+    F: Future<Output = worker::Result<T>>,
+{
+    let future = SendWrapper::new(future);
+    async move {
+        future
+            .await
+            .map_err(|e| async_graphql::Error::new(e.to_string()))
+    }
+}
+
+fn d1(ctx: &Context<'_>) -> worker::Result<Database> {
+    ctx.data_unchecked::<SendWrapper<Env>>().d1("failcat_db")
+}
+
+/// Loads batch on a task of their own, which in a worker has to be spawned on the one thread.
+fn loader<T: Send + Sync + 'static>(loader: T) -> DataLoader<T> {
+    DataLoader::new(loader, wasm_bindgen_futures::spawn_local)
+}
+
+/// Dealers by code, so a page of cars looks its dealers up in one query.
+pub struct DealerLoader(SendWrapper<Env>);
+
+#[async_trait]
+impl Loader<String> for DealerLoader {
+    type Value = Dealer;
+    type Error = async_graphql::Error;
+
+    async fn load(&self, keys: &[String]) -> async_graphql::Result<HashMap<String, Dealer>> {
+        let dealers = resolve(async move {
+            let repo = DealerRepository::new(self.0.d1("failcat_db")?);
+            repo.get_many(keys).await
+        })
+        .await?;
+        Ok(dealers
+            .into_iter()
+            .map(|dealer| (dealer.dealer_code.clone(), dealer))
+            .collect())
+    }
+}
+
+/// Models by code.
+pub struct ModelLoader(SendWrapper<Env>);
+
+#[async_trait]
+impl Loader<String> for ModelLoader {
+    type Value = CarModel;
+    type Error = async_graphql::Error;
+
+    async fn load(&self, keys: &[String]) -> async_graphql::Result<HashMap<String, CarModel>> {
+        let models = resolve(async move {
+            let repo = CarModelRepository::new(self.0.d1("failcat_db")?);
+            repo.get_many(keys).await
+        })
+        .await?;
+        Ok(models
+            .into_iter()
+            .map(|model| (model.model_code.clone(), model))
+            .collect())
+    }
+}
+
+/// Options and pricing by car id. `options` and `pricing` share the one load per car.
+pub struct OptionsLoader(SendWrapper<Env>);
+
+#[async_trait]
+impl Loader<CarId> for OptionsLoader {
+    type Value = StoredOptions;
+    type Error = async_graphql::Error;
+
+    async fn load(&self, keys: &[CarId]) -> async_graphql::Result<HashMap<CarId, StoredOptions>> {
+        resolve(async move {
+            let repo = OptionRepository::new(self.0.d1("failcat_db")?);
+            repo.get_for_cars(keys).await
+        })
+        .await
+    }
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Status {
+    #[default]
+    Ok,
+    Broken,
+    Pending,
+}
+
+impl From<Status> for CarStatus {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Ok => CarStatus::Ok,
+            Status::Broken => CarStatus::Broken,
+            Status::Pending => CarStatus::Pending,
+        }
+    }
+}
+
+impl From<CarStatus> for Status {
+    fn from(status: CarStatus) -> Self {
+        match status {
+            CarStatus::Ok => Status::Ok,
+            CarStatus::Broken => Status::Broken,
+            CarStatus::Pending => Status::Pending,
+        }
+    }
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Order {
+    Id,
+    #[default]
+    Serial,
+}
+
+/// The filters `/cars` takes.
+#[derive(InputObject, Debug, Default)]
+pub struct CarFilter {
+    pub dealer: Option<String>,
+    pub minimum_serial: Option<i32>,
+    pub maximum_serial: Option<i32>,
+    pub minimum_id: Option<i32>,
+    pub maximum_id: Option<i32>,
+    pub status: Option<Status>,
+    pub has_option: Option<String>,
+    pub msrp_min: Option<i32>,
+    pub msrp_max: Option<i32>,
+}
+
+#[derive(InputObject, Debug)]
+pub struct Page {
+    #[graphql(default = 10)]
+    pub limit: i32,
+    #[graphql(default)]
+    pub offset: i32,
+    #[graphql(default)]
+    pub order: Order,
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Page {
+            limit: DEFAULT_PAGE_SIZE,
+            offset: 0,
+            order: Order::default(),
+        }
+    }
+}
+
+impl Page {
+    fn limit(&self) -> i32 {
+        self.limit.clamp(0, MAX_PAGE_SIZE)
+    }
+}
+
+fn car_query(filter: CarFilter, page: Page) -> CarQuery {
+    CarQuery {
+        dealer: filter.dealer,
+        perpage: Some(page.limit()),
+        offset: Some(page.offset.max(0)),
+        order: Some(match page.order {
+            Order::Id => CarOrder::Id,
+            Order::Serial => CarOrder::Serial,
+        }),
+        minimum_serial: filter.minimum_serial.map(SerialNumber),
+        maximum_serial: filter.maximum_serial.map(SerialNumber),
+        minimum_id: filter.minimum_id.map(SerialNumber),
+        maximum_maximum: filter.maximum_id.map(SerialNumber),
+        status: filter.status.map(CarStatus::from),
+        has_option: filter.has_option,
+        msrp_min: filter.msrp_min,
+        msrp_max: filter.msrp_max,
+    }
+}
+
+async fn cars(
+    ctx: &Context<'_>,
+    filter: CarFilter,
+    page: Page,
+) -> async_graphql::Result<Vec<CarNode>> {
+    let query = car_query(filter, page);
+    let cars = resolve(async move {
+        CarRepository::new(d1(ctx)?)
+            .get_all_paginated(query)
+            .await
+    })
+    .await?;
+    Ok(cars.into_iter().map(CarNode).collect())
+}
+
+pub struct CarNode(Car);
+
+#[Object(name = "Car")]
+impl CarNode {
+    async fn id(&self) -> Option<i32> {
+        self.0.id.map(|id| id.0)
+    }
+
+    async fn vin(&self) -> &str {
+        &self.0.vin
+    }
+
+    async fn serial_number(&self) -> i32 {
+        self.0.serial_number.0
+    }
+
+    async fn status(&self) -> Status {
+        self.0.status.into()
+    }
+
+    async fn model_year(&self) -> &str {
+        &self.0.model_year
+    }
+
+    async fn car_model(&self) -> &str {
+        &self.0.car_model
+    }
+
+    async fn model_code(&self) -> Option<&str> {
+        self.0.model_code.as_deref()
+    }
+
+    async fn opt_code(&self) -> &str {
+        &self.0.opt_code
+    }
+
+    async fn ext_color(&self) -> &str {
+        &self.0.ext_color
+    }
+
+    async fn ext_color_name(&self) -> Option<&str> {
+        self.0.ext_color_name.as_deref()
+    }
+
+    async fn ext_paint_code(&self) -> Option<&str> {
+        self.0.ext_paint_code.as_deref()
+    }
+
+    async fn int_color(&self) -> &str {
+        &self.0.int_color
+    }
+
+    async fn int_color_name(&self) -> Option<&str> {
+        self.0.int_color_name.as_deref()
+    }
+
+    async fn int_material(&self) -> Option<&str> {
+        self.0.int_material.as_deref()
+    }
+
+    async fn ship_to(&self) -> &str {
+        &self.0.ship_to
+    }
+
+    async fn sold_to(&self) -> &str {
+        &self.0.sold_to
+    }
+
+    async fn created_date(&self) -> DateTime<Utc> {
+        self.0.created_date
+    }
+
+    /// The dealer the car was sold to.
+    async fn dealer(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Dealer>> {
+        let loader = ctx.data_unchecked::<DataLoader<DealerLoader>>();
+        loader.load_one(self.0.sold_to.clone()).await
+    }
+
+    async fn model(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<CarModel>> {
+        let model_code = match &self.0.model_code {
+            Some(model_code) => model_code.clone(),
+            None => return Ok(None),
+        };
+        let loader = ctx.data_unchecked::<DataLoader<ModelLoader>>();
+        loader.load_one(model_code).await
+    }
+
+    /// Options parsed from the car's sticker, empty until it's been parsed.
+    async fn options(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<CarOption>> {
+        let stored = self.stored_options(ctx).await?;
+        Ok(stored.map(|stored| stored.options).unwrap_or_default())
+    }
+
+    async fn pricing(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<CarPricing>> {
+        let stored = self.stored_options(ctx).await?;
+        Ok(stored.and_then(|stored| stored.pricing))
+    }
+}
+
+impl CarNode {
+    async fn stored_options(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<Option<StoredOptions>> {
+        let car_id = match self.0.id {
+            Some(car_id) => car_id,
+            None => return Ok(None),
+        };
+        let loader = ctx.data_unchecked::<DataLoader<OptionsLoader>>();
+        loader.load_one(car_id).await
+    }
+}
+
+#[ComplexObject]
+impl Dealer {
+    /// Cars sold to this dealer.
+    #[graphql(complexity = "page.limit() as usize * child_complexity")]
+    async fn cars(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] filter: CarFilter,
+        #[graphql(default)] page: Page,
+    ) -> async_graphql::Result<Vec<CarNode>> {
+        let filter = CarFilter {
+            dealer: Some(self.dealer_code.clone()),
+            ..filter
+        };
+        cars(ctx, filter, page).await
+    }
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    async fn car(&self, ctx: &Context<'_>, id: i32) -> async_graphql::Result<Option<CarNode>> {
+        let car = resolve(async move {
+            let d1 = d1(ctx)?;
+            let statement = d1.prepare("SELECT * FROM cars WHERE id = ?");
+            statement.bind(&[id.into()])?.first::<Car>(None).await
+        })
+        .await?;
+        Ok(car.map(CarNode))
+    }
+
+    async fn car_by_serial(
+        &self,
+        ctx: &Context<'_>,
+        serial_number: i32,
+    ) -> async_graphql::Result<Option<CarNode>> {
+        let car = resolve(async move {
+            Car::from_d1_by_serial(SerialNumber(serial_number), &d1(ctx)?).await
+        })
+        .await?;
+        Ok(car.map(CarNode))
+    }
+
+    #[graphql(complexity = "page.limit() as usize * child_complexity")]
+    async fn cars(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] filter: CarFilter,
+        #[graphql(default)] page: Page,
+    ) -> async_graphql::Result<Vec<CarNode>> {
+        cars(ctx, filter, page).await
+    }
+
+    async fn dealer(
+        &self,
+        ctx: &Context<'_>,
+        dealer_code: String,
+    ) -> async_graphql::Result<Option<Dealer>> {
+        resolve(async move { DealerRepository::new(d1(ctx)?).get(&dealer_code).await }).await
+    }
+
+    /// Dealers by code. `order` doesn't apply here.
+    #[graphql(complexity = "page.limit() as usize * child_complexity")]
+    async fn dealers(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] page: Page,
+    ) -> async_graphql::Result<Vec<Dealer>> {
+        let (limit, offset) = (page.limit(), page.offset.max(0));
+        resolve(async move {
+            DealerRepository::new(d1(ctx)?)
+                .get_paginated(limit, offset)
+                .await
+        })
+        .await
+    }
+
+    async fn model(
+        &self,
+        ctx: &Context<'_>,
+        model_code: String,
+    ) -> async_graphql::Result<Option<CarModel>> {
+        resolve(async move { CarModelRepository::new(d1(ctx)?).get(&model_code).await }).await
+    }
+
+    #[graphql(complexity = "limit.clamp(0, MAX_PAGE_SIZE) as usize * child_complexity")]
+    async fn scraper_logs(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 10)] limit: i32,
+        #[graphql(default)] offset: i32,
+    ) -> async_graphql::Result<Vec<ScraperLog>> {
+        let limit = limit.clamp(0, MAX_PAGE_SIZE);
+        resolve(async move {
+            ScraperLogRepository::new(d1(ctx)?)
+                .get_recent(limit, offset.max(0))
+                .await
+        })
+        .await
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+
+use sha2::{Digest, Sha256};
+use worker::*;
+
+use crate::models::Car;
+
+// Cache keys only need to be valid urls, they never leave the worker
+const CACHE_ORIGIN: &str = "https://cache.failcat.internal";
+// Every isolate reads and bumps generations on this one instance
+const GENERATIONS_NAME: &str = "http-cache";
+
+/// Something a cached response was built from. Writes bump the tag's generation, which is part
+/// of the cache key, so every response carrying the tag is missed from then on in every data
+/// center. Deleting from the Cache API would only reach the one the write ran in, and couldn't
+/// name every filtered `/cars` key anyway.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheTag {
+    Cars,
+    Car(i32),
+    Dealers,
+    Dealer(String),
+}
+
+impl CacheTag {
+    fn key(&self) -> String {
+        match self {
+            CacheTag::Cars => "cars".to_string(),
+            CacheTag::Car(id) => format!("car:{id}"),
+            CacheTag::Dealers => "dealers".to_string(),
+            CacheTag::Dealer(code) => format!("dealer:{code}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CachePolicy {
+    /// Seconds a response is served from the cache, and told to clients as `max-age`.
+    pub ttl: u32,
+    pub tags: Vec<CacheTag>,
+}
+
+/// How long each read route is cached for, `None` for everything that isn't cached.
+pub fn policy_for(req: &Request) -> Option<CachePolicy> {
+    if req.method() != Method::Get {
+        return None;
+    }
+    let path = req.path();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let (ttl, tag) = match segments.as_slice() {
+        ["cars"] => (60, CacheTag::Cars),
+        // Parsed so `/car/007` and `/car/7` share a tag, anything else isn't a car
+        ["car", id] => (300, CacheTag::Car(id.parse().ok()?)),
+        ["dealers"] => (600, CacheTag::Dealers),
+        ["dealers", code, "stats"] => (300, CacheTag::Dealer(code.to_string())),
+        _ => return None,
+    };
+    Some(CachePolicy {
+        ttl,
+        tags: vec![tag],
+    })
+}
+
+/// Holds the generation of every cache tag. An object handles its requests one at a time, so
+/// concurrent bumps never lose each other, and a read sees every bump that finished before it.
+#[durable_object]
+pub struct CacheGenerations {
+    state: State,
+    generations: HashMap<String, u64>,
+}
+
+impl CacheGenerations {
+    async fn get(&mut self, key: &str) -> u64 {
+        if let Some(generation) = self.generations.get(key) {
+            return *generation;
+        }
+        let generation = self.state.storage().get::<u64>(key).await.unwrap_or(0);
+        self.generations.insert(key.to_string(), generation);
+        generation
+    }
+}
+
+#[durable_object]
+impl DurableObject for CacheGenerations {
+    fn new(state: State, _env: Env) -> Self {
+        CacheGenerations {
+            state,
+            generations: HashMap::new(),
+        }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let keys: Vec<String> = req.json().await?;
+        let mut generations = vec![];
+        for key in &keys {
+            generations.push(self.get(key).await);
+        }
+        match req.path().as_str() {
+            "/read" => Response::from_json(&generations),
+            "/bump" => {
+                for (key, generation) in keys.into_iter().zip(generations) {
+                    self.state.storage().put(&key, generation + 1).await?;
+                    self.generations.insert(key, generation + 1);
+                }
+                Response::empty()
+            }
+            _ => Response::error("Not Found", 404),
+        }
+    }
+}
+
+/// Talks to the `CacheGenerations` object behind the `CACHE_GENERATIONS` binding.
+pub struct Generations {
+    stub: Stub,
+}
+
+impl Generations {
+    pub fn new(env: &Env) -> Result<Self> {
+        let stub = env
+            .durable_object("CACHE_GENERATIONS")?
+            .id_from_name(GENERATIONS_NAME)?
+            .get_stub()?;
+        Ok(Generations { stub })
+    }
+
+    async fn post(&self, path: &str, tags: &[CacheTag]) -> Result<Response> {
+        let keys: Vec<String> = tags.iter().map(CacheTag::key).collect();
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post)
+            .with_body(Some(serde_json::to_string(&keys)?.into()));
+        let request = Request::new_with_init(&format!("https://http-cache{path}"), &init)?;
+        let response = self.stub.fetch_with_request(request).await?;
+        match response.status_code() {
+            200..=299 => Ok(response),
+            status => Err(format!("cache generations {path} answered {status}").into()),
+        }
+    }
+
+    async fn read(&self, tags: &[CacheTag]) -> Result<Vec<u64>> {
+        self.post("/read", tags).await?.json().await
+    }
+
+    /// Invalidates every cached response carrying one of the tags.
+    pub async fn purge(&self, tags: &[CacheTag]) -> Result<()> {
+        self.post("/bump", tags).await?;
+        Ok(())
+    }
+}
+
+/// Everything a write to this car can change: itself, the car list and the dealer pages,
+/// including the one it was under before when `previous_sold_to` differs.
+pub async fn purge_car(
+    generations: &Generations,
+    car: &Car,
+    previous_sold_to: Option<&str>,
+) -> Result<()> {
+    let mut tags = vec![
+        CacheTag::Cars,
+        CacheTag::Dealers,
+        CacheTag::Dealer(car.sold_to.clone()),
+    ];
+    if let Some(previous_sold_to) = previous_sold_to.filter(|sold_to| *sold_to != car.sold_to) {
+        tags.push(CacheTag::Dealer(previous_sold_to.to_string()));
+    }
+    if let Some(id) = car.id {
+        tags.push(CacheTag::Car(id.0));
+    }
+    generations.purge(&tags).await
+}
+
+/// `"..."` from the first half of the body's sha256.
+fn etag(body: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(&Sha256::digest(body)[..16]))
+}
+
+fn matches_if_none_match(req: &Request, etag: &str) -> bool {
+    let if_none_match = match req.headers().get("If-None-Match") {
+        Ok(Some(if_none_match)) => if_none_match,
+        _ => return false,
+    };
+    if_none_match.split(',').any(|candidate| {
+        let candidate = candidate.trim();
+        candidate == "*" || candidate.trim_start_matches("W/") == etag
+    })
+}
+
+fn not_modified(headers: &Headers) -> Result<Response> {
+    let mut response = Response::empty()?.with_status(304);
+    for name in ["ETag", "Cache-Control"] {
+        if let Some(value) = headers.get(name)? {
+            response.headers_mut().set(name, &value)?;
+        }
+    }
+    Ok(response)
+}
+
+/// The cache entry for one request, keyed by path, sorted query params and the current
+/// generation of each of its tags.
+pub struct HttpCache {
+    cache: Cache,
+    key: String,
+    ttl: u32,
+}
+
+impl HttpCache {
+    pub async fn new(req: &Request, env: &Env, policy: CachePolicy) -> Result<Self> {
+        let generations: Vec<String> = Generations::new(env)?
+            .read(&policy.tags)
+            .await?
+            .iter()
+            .map(u64::to_string)
+            .collect();
+
+        let url = req.url()?;
+        let mut params: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        params.sort();
+        let mut key = Url::parse(CACHE_ORIGIN).map_err(|e| Error::RustError(e.to_string()))?;
+        key.set_path(url.path());
+        key.query_pairs_mut()
+            .extend_pairs(params)
+            .append_pair("_generation", &generations.join("."));
+
+        Ok(HttpCache {
+            cache: Cache::default(),
+            key: key.to_string(),
+            ttl: policy.ttl,
+        })
+    }
+
+    /// The cached response, or a 304 when the client already has it.
+    pub async fn lookup(&self, req: &Request) -> Result<Option<Response>> {
+        let mut hit = match self.cache.get(self.key.as_str(), false).await? {
+            Some(hit) => hit,
+            None => return Ok(None),
+        };
+        let headers = hit.headers().clone();
+        let etag = headers.get("ETag")?.unwrap_or_default();
+        if matches_if_none_match(req, &etag) {
+            return Ok(Some(not_modified(&headers)?));
+        }
+        // Headers on a response out of the cache are immutable, and CORS still has to add its own
+        let body = hit.bytes().await?;
+        headers.set("X-Cache", "HIT")?;
+        Ok(Some(Response::from_bytes(body)?.with_headers(headers)))
+    }
+
+    /// Tags a fresh response with its ETag and caches it. Only successful responses are kept.
+    pub async fn store(&self, req: &Request, mut response: Response) -> Result<Response> {
+        if response.status_code() != 200 {
+            return Ok(response);
+        }
+        let headers = response.headers().clone();
+        let body = response.bytes().await?;
+        headers.set("ETag", &etag(&body))?;
+        headers.set("Cache-Control", &format!("public, max-age={}", self.ttl))?;
+
+        let cached = Response::from_bytes(body.clone())?.with_headers(headers.clone());
+        if let Err(e) = self.cache.put(self.key.as_str(), cached).await {
+            console_error!("couldn't cache {}: {:?}", self.key, e);
+        }
+
+        if matches_if_none_match(req, &headers.get("ETag")?.unwrap_or_default()) {
+            return not_modified(&headers);
+        }
+        headers.set("X-Cache", "MISS")?;
+        Ok(Response::from_bytes(body)?.with_headers(headers))
+    }
+}
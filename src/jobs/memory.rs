@@ -0,0 +1,330 @@
+use std::cell::RefCell;
+use std::future::Future;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::Serialize;
+use worker::*;
+
+use super::*;
+
+// Mirrors the consumer settings in wrangler.toml
+const MAX_RETRIES: u32 = 10;
+const RETRY_DELAY_MS: i64 = 5 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy)]
+struct Pending {
+    job: ScrapeJob,
+    attempts: u32,
+    not_before: i64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct QueueRunReport {
+    pub acked: Vec<ScrapeJob>,
+    pub retried: Vec<ScrapeJob>,
+    pub dead_lettered: Vec<ScrapeJob>,
+}
+
+/// A stand-in for the Cloudflare queue with the same retry and dead-letter behaviour, for tests.
+/// Nothing is delivered until `run` is called.
+pub struct InMemoryQueue {
+    pending: RefCell<Vec<Pending>>,
+    dead_letters: RefCell<Vec<ScrapeJob>>,
+    clock: fn() -> i64,
+}
+
+impl Default for InMemoryQueue {
+    fn default() -> Self {
+        Self::with_clock(|| Utc::now().timestamp_millis())
+    }
+}
+
+impl InMemoryQueue {
+    pub fn with_clock(clock: fn() -> i64) -> Self {
+        InMemoryQueue {
+            pending: RefCell::new(vec![]),
+            dead_letters: RefCell::new(vec![]),
+            clock,
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.borrow().len()
+    }
+
+    pub fn dead_letters(&self) -> Vec<ScrapeJob> {
+        self.dead_letters.borrow().clone()
+    }
+
+    fn retry(&self, mut pending: Pending, now: i64, report: &mut QueueRunReport) {
+        pending.attempts += 1;
+        if pending.attempts > MAX_RETRIES {
+            self.dead_letters.borrow_mut().push(pending.job);
+            report.dead_lettered.push(pending.job);
+        } else {
+            pending.not_before = now + RETRY_DELAY_MS;
+            self.pending.borrow_mut().push(pending);
+            report.retried.push(pending.job);
+        }
+    }
+
+    /// Delivers every job that's due as one batch through `consume_batch`, like the Queues
+    /// consumer does.
+    pub async fn run<F, Fut>(&self, process: F) -> QueueRunReport
+    where
+        F: FnMut(ScrapeJob) -> Fut,
+        Fut: Future<Output = Result<JobOutcome>>,
+    {
+        let now = (self.clock)();
+        let (batch, waiting): (Vec<Pending>, Vec<Pending>) = self
+            .pending
+            .borrow_mut()
+            .drain(..)
+            .partition(|pending| pending.not_before <= now);
+        *self.pending.borrow_mut() = waiting;
+
+        let report = RefCell::new(QueueRunReport::default());
+        let deliveries: Vec<Delivery> = batch
+            .into_iter()
+            .map(|pending| Delivery {
+                pending,
+                queue: self,
+                now,
+                report: &report,
+            })
+            .collect();
+        consume_batch(deliveries, process).await;
+        report.into_inner()
+    }
+}
+
+/// One job handed to `consume_batch`, acking or retrying it back into the queue.
+struct Delivery<'a> {
+    pending: Pending,
+    queue: &'a InMemoryQueue,
+    now: i64,
+    report: &'a RefCell<QueueRunReport>,
+}
+
+impl JobMessage for Delivery<'_> {
+    fn job(&self) -> &ScrapeJob {
+        &self.pending.job
+    }
+
+    fn ack(&self) {
+        self.report.borrow_mut().acked.push(self.pending.job);
+    }
+
+    fn retry(&self) {
+        self.queue
+            .retry(self.pending, self.now, &mut self.report.borrow_mut());
+    }
+}
+
+#[async_trait(?Send)]
+impl JobQueue for InMemoryQueue {
+    async fn send(&self, jobs: Vec<ScrapeJob>) -> Result<()> {
+        let now = (self.clock)();
+        self.pending
+            .borrow_mut()
+            .extend(jobs.into_iter().map(|job| Pending {
+                job,
+                attempts: 0,
+                not_before: now,
+            }));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use futures::executor::block_on;
+
+    use crate::coordinator::{InMemoryCoordinator, WorkKind};
+    use crate::scraper::vinlookup::SAP_LIMITS_EXCEEDED;
+
+    use super::*;
+
+    thread_local! {
+        static NOW: Cell<i64> = Cell::new(0);
+    }
+
+    fn now() -> i64 {
+        NOW.with(|now| now.get())
+    }
+
+    fn advance(ms: i64) {
+        NOW.with(|now| now.set(now.get() + ms));
+    }
+
+    fn job(serial_number: i32, priority: i32) -> ScrapeJob {
+        ScrapeJob {
+            serial_number: SerialNumber(serial_number),
+            priority,
+            reason: JobReason::Manual,
+        }
+    }
+
+    #[test]
+    fn acked_jobs_are_done() {
+        let queue = InMemoryQueue::with_clock(now);
+        block_on(queue.send(vec![job(1, 0), job(2, 5)])).unwrap();
+
+        let report = block_on(queue.run(|_| async { Ok(JobOutcome::Ack) }));
+        assert_eq!(report.acked, vec![job(2, 5), job(1, 0)]);
+        assert!(report.retried.is_empty());
+        assert_eq!(queue.pending(), 0);
+    }
+
+    #[test]
+    fn limits_retry_the_rest_of_the_batch_after_a_delay() {
+        let queue = InMemoryQueue::with_clock(now);
+        block_on(queue.send(vec![job(1, 2), job(2, 1), job(3, 0)])).unwrap();
+
+        let mut tried = vec![];
+        let report = block_on(queue.run(|job| {
+            tried.push(job);
+            async { Ok(JobOutcome::Limited) }
+        }));
+        assert_eq!(tried, vec![job(1, 2)]);
+        assert_eq!(report.retried, vec![job(1, 2), job(2, 1), job(3, 0)]);
+        assert_eq!(queue.pending(), 3);
+
+        // Nothing is due until the retry delay has passed
+        advance(RETRY_DELAY_MS - 1);
+        let report = block_on(queue.run(|_| async { Ok(JobOutcome::Ack) }));
+        assert!(report.acked.is_empty());
+
+        advance(1);
+        let report = block_on(queue.run(|_| async { Ok(JobOutcome::Ack) }));
+        assert_eq!(report.acked.len(), 3);
+        assert_eq!(queue.pending(), 0);
+    }
+
+    #[test]
+    fn jobs_are_dead_lettered_after_max_retries() {
+        let queue = InMemoryQueue::with_clock(now);
+        block_on(queue.send(vec![job(1, 0)])).unwrap();
+
+        for _ in 0..MAX_RETRIES {
+            let report = block_on(queue.run(|_| async { Err("upstream down".into()) }));
+            assert_eq!(report.retried, vec![job(1, 0)]);
+            advance(RETRY_DELAY_MS);
+        }
+        let report = block_on(queue.run(|_| async { Ok(JobOutcome::Retry) }));
+        assert!(report.retried.is_empty());
+        assert_eq!(report.dead_lettered, vec![job(1, 0)]);
+        assert_eq!(queue.dead_letters(), vec![job(1, 0)]);
+        assert_eq!(queue.pending(), 0);
+    }
+
+    /// Scrapes with `scrape` and remembers which serials it was asked for.
+    struct StubScraper {
+        stored: Vec<SerialNumber>,
+        scrape: fn(SerialNumber) -> Result<bool>,
+        scraped: RefCell<Vec<SerialNumber>>,
+    }
+
+    impl StubScraper {
+        fn new(scrape: fn(SerialNumber) -> Result<bool>) -> Self {
+            StubScraper {
+                stored: vec![],
+                scrape,
+                scraped: RefCell::new(vec![]),
+            }
+        }
+
+        fn scraped(&self) -> Vec<i32> {
+            self.scraped
+                .borrow()
+                .iter()
+                .map(|serial| serial.0)
+                .collect()
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl JobScraper for StubScraper {
+        async fn is_stored(&self, serial_number: SerialNumber) -> Result<bool> {
+            Ok(self.stored.contains(&serial_number))
+        }
+
+        async fn scrape(&self, serial_number: SerialNumber) -> Result<bool> {
+            self.scraped.borrow_mut().push(serial_number);
+            (self.scrape)(serial_number)
+        }
+    }
+
+    fn process(
+        queue: &InMemoryQueue,
+        scraper: &StubScraper,
+        coordinator: &InMemoryCoordinator,
+    ) -> QueueRunReport {
+        block_on(queue.run(|job| async move { process_job(scraper, coordinator, &job).await }))
+    }
+
+    #[test]
+    fn processed_jobs_are_acked_and_their_leases_completed() {
+        let queue = InMemoryQueue::with_clock(now);
+        let coordinator = InMemoryCoordinator::with_clock(now);
+        let mut scraper = StubScraper::new(|serial| Ok(serial.0 == 1));
+        scraper.stored.push(SerialNumber(3));
+        block_on(queue.send(vec![job(1, 2), job(2, 1), job(3, 0)])).unwrap();
+
+        let report = process(&queue, &scraper, &coordinator);
+        assert_eq!(report.acked, vec![job(1, 2), job(2, 1), job(3, 0)]);
+        // Already stored, so never scraped
+        assert_eq!(scraper.scraped(), vec![1, 2]);
+        let status = block_on(coordinator.status()).unwrap();
+        assert!(status.in_flight.is_empty());
+        assert!(status.queued.is_empty());
+    }
+
+    #[test]
+    fn jobs_for_a_serial_in_flight_are_retried() {
+        let queue = InMemoryQueue::with_clock(now);
+        let coordinator = InMemoryCoordinator::with_clock(now);
+        let scraper = StubScraper::new(|_| Ok(true));
+        let item = WorkItem::new(SerialNumber(1), WorkKind::Backfill);
+        block_on(coordinator.claim("cron", item)).unwrap().unwrap();
+        block_on(queue.send(vec![job(1, 0)])).unwrap();
+
+        let report = process(&queue, &scraper, &coordinator);
+        assert_eq!(report.retried, vec![job(1, 0)]);
+        assert!(scraper.scraped().is_empty());
+    }
+
+    #[test]
+    fn upstream_limits_pause_the_coordinator_and_retry_the_batch() {
+        let queue = InMemoryQueue::with_clock(now);
+        let coordinator = InMemoryCoordinator::with_clock(now);
+        let scraper = StubScraper::new(|_| Err(SAP_LIMITS_EXCEEDED.into()));
+        block_on(queue.send(vec![job(1, 1), job(2, 0)])).unwrap();
+
+        let report = process(&queue, &scraper, &coordinator);
+        assert_eq!(report.retried, vec![job(1, 1), job(2, 0)]);
+        assert_eq!(scraper.scraped(), vec![1]);
+        let status = block_on(coordinator.status()).unwrap();
+        assert!(status.paused_until.is_some());
+        assert!(status.in_flight.is_empty());
+    }
+
+    #[test]
+    fn failed_scrapes_are_left_to_the_queue_to_retry() {
+        let queue = InMemoryQueue::with_clock(now);
+        let coordinator = InMemoryCoordinator::with_clock(now);
+        let scraper = StubScraper::new(|_| Err("timed out".into()));
+        block_on(queue.send(vec![job(1, 0), job(2, 0)])).unwrap();
+
+        let report = process(&queue, &scraper, &coordinator);
+        assert_eq!(report.retried, vec![job(1, 0), job(2, 0)]);
+        assert_eq!(scraper.scraped(), vec![1, 2]);
+        // Released rather than requeued, so the coordinator doesn't try it a second way
+        let status = block_on(coordinator.status()).unwrap();
+        assert!(status.in_flight.is_empty());
+        assert!(status.queued.is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+use std::cmp::Reverse;
+use std::future::Future;
+
+use async_trait::async_trait;
+use derive_more::Display;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::coordinator::{ScrapeCoordinator, WorkItem, WorkKind, WorkOutcome};
+use crate::models::{CarStore, SerialNumber};
+use crate::scraper::vinlookup::{self, attempt_to_scrape_from_serial};
+
+#[cfg(test)]
+pub mod memory;
+pub mod queue;
+
+#[cfg(test)]
+pub use memory::InMemoryQueue;
+pub use queue::ScrapeQueue;
+
+/// Why a job was queued, which decides how the coordinator ranks it against other work.
+#[derive(
+    Debug, Display, Default, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum JobReason {
+    #[default]
+    #[display(fmt = "manual")]
+    Manual,
+    #[display(fmt = "watched")]
+    Watched,
+    #[display(fmt = "backfill")]
+    Backfill,
+}
+
+impl JobReason {
+    pub fn kind(&self) -> WorkKind {
+        match self {
+            JobReason::Watched => WorkKind::Watched,
+            JobReason::Manual | JobReason::Backfill => WorkKind::Backfill,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeJob {
+    pub serial_number: SerialNumber,
+    /// Higher runs first within a batch.
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub reason: JobReason,
+}
+
+/// What the consumer should do with a job's message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobOutcome {
+    Ack,
+    /// Try again after the usual retry delay.
+    Retry,
+    /// Upstream limits were hit, nothing else in the batch is going to get through either.
+    Limited,
+}
+
+/// Where HTTP triggers put scrape jobs. Cloudflare Queues in production, `InMemoryQueue` in
+/// tests.
+#[async_trait(?Send)]
+pub trait JobQueue {
+    async fn send(&self, jobs: Vec<ScrapeJob>) -> Result<()>;
+}
+
+/// A delivered job. Queues messages in production, `InMemoryQueue` deliveries in tests.
+pub trait JobMessage {
+    fn job(&self) -> &ScrapeJob;
+    fn ack(&self);
+    fn retry(&self);
+}
+
+impl JobMessage for Message<ScrapeJob> {
+    fn job(&self) -> &ScrapeJob {
+        &self.body
+    }
+
+    fn ack(&self) {
+        Message::ack(self)
+    }
+
+    fn retry(&self) {
+        Message::retry(self)
+    }
+}
+
+/// Runs a batch through `process`, highest priority first. Once upstream limits are hit the
+/// rest of the batch is retried without being tried.
+pub async fn consume_batch<M, F, Fut>(mut messages: Vec<M>, mut process: F)
+where
+    M: JobMessage,
+    F: FnMut(ScrapeJob) -> Fut,
+    Fut: Future<Output = Result<JobOutcome>>,
+{
+    messages.sort_by_key(|message| Reverse(message.job().priority));
+    let mut limited = false;
+    for message in messages {
+        if limited {
+            message.retry();
+            continue;
+        }
+        match process(*message.job()).await {
+            Ok(JobOutcome::Ack) => message.ack(),
+            Ok(JobOutcome::Retry) => message.retry(),
+            Ok(JobOutcome::Limited) => {
+                limited = true;
+                message.retry();
+            }
+            Err(e) => {
+                console_error!("scrape job {:?} failed: {:?}", message.job(), e);
+                message.retry();
+            }
+        }
+    }
+}
+
+/// What a job needs besides the coordinator. The worker's `Env` in production.
+#[async_trait(?Send)]
+pub trait JobScraper {
+    async fn is_stored(&self, serial_number: SerialNumber) -> Result<bool>;
+
+    /// Whether a car turned up for the serial.
+    async fn scrape(&self, serial_number: SerialNumber) -> Result<bool>;
+}
+
+#[async_trait(?Send)]
+impl JobScraper for Env {
+    async fn is_stored(&self, serial_number: SerialNumber) -> Result<bool> {
+        Ok(CarStore::new(self)?.get(serial_number).await?.is_some())
+    }
+
+    async fn scrape(&self, serial_number: SerialNumber) -> Result<bool> {
+        Ok(attempt_to_scrape_from_serial(serial_number, self)
+            .await?
+            .is_some())
+    }
+}
+
+/// Runs one job. The coordinator claim keeps queued jobs from scraping a serial the cron or a
+/// manual trigger already has in flight.
+pub async fn process_job(
+    scraper: &dyn JobScraper,
+    coordinator: &dyn ScrapeCoordinator,
+    job: &ScrapeJob,
+) -> Result<JobOutcome> {
+    // Scraped since it was queued, most likely by an earlier attempt of this same job
+    if scraper.is_stored(job.serial_number).await? {
+        return Ok(JobOutcome::Ack);
+    }
+    let item = WorkItem::new(job.serial_number, job.reason.kind());
+    let lease = match coordinator.claim("queue", item).await? {
+        Some(lease) => lease,
+        None => return Ok(JobOutcome::Retry),
+    };
+    let (outcome, job_outcome) = match scraper.scrape(job.serial_number).await {
+        Ok(true) => (WorkOutcome::Found, JobOutcome::Ack),
+        Ok(false) => (WorkOutcome::NotFound, JobOutcome::Ack),
+        Err(e) if vinlookup::is_limits_exceeded(&e) => {
+            (WorkOutcome::LimitsExceeded, JobOutcome::Limited)
+        }
+        Err(e) => {
+            console_error!("{} job for {} failed: {:?}", job.reason, job.serial_number, e);
+            (WorkOutcome::Released, JobOutcome::Retry)
+        }
+    };
+    coordinator.complete(&lease, outcome).await?;
+    Ok(job_outcome)
+}
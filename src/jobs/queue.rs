@@ -0,0 +1,45 @@
+use async_trait::async_trait;
+use worker::*;
+
+use crate::coordinator::DurableCoordinator;
+
+use super::*;
+
+pub const SCRAPE_QUEUE: &str = "SCRAPE_JOBS";
+// Queues takes at most this many messages per `send_batch`
+const MAX_SEND_BATCH: usize = 100;
+
+/// The `SCRAPE_JOBS` queue. Retry delay, retry count and the dead-letter queue are consumer
+/// settings in `wrangler.toml`, so a job that keeps failing ends up in `scrape-jobs-dlq`.
+pub struct ScrapeQueue {
+    queue: Queue,
+}
+
+impl ScrapeQueue {
+    pub fn new(env: &Env) -> Result<Self> {
+        Ok(ScrapeQueue {
+            queue: env.queue(SCRAPE_QUEUE)?,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl JobQueue for ScrapeQueue {
+    async fn send(&self, jobs: Vec<ScrapeJob>) -> Result<()> {
+        for chunk in jobs.chunks(MAX_SEND_BATCH) {
+            self.queue.send_batch(chunk.to_vec()).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Consumes a batch from the `SCRAPE_JOBS` queue.
+pub async fn consume(batch: MessageBatch<ScrapeJob>, env: &Env) -> Result<()> {
+    let coordinator = DurableCoordinator::new(env)?;
+    let coordinator = &coordinator;
+    consume_batch(batch.messages()?, |job| async move {
+        process_job(env, coordinator, &job).await
+    })
+    .await;
+    Ok(())
+}
@@ -1,16 +1,49 @@
 #![allow(clippy::too_many_arguments)]
 
+use auth::{Access, ApiKeyRepository, NewApiKey};
+use chrono::DateTime;
 use common::ScrapeResponse;
-use models::{highest_serial, Car, CarId, CarQuery, CarRepository, DealerRepository, SerialNumber};
-use reqwest_wasm::header::{HeaderMap, HeaderValue};
-use scraper::vinlookup::{
-    self, attempt_to_scrape_from_serial, get_possible_vins_from_serial, vinlookup,
+use coordinator::{DurableCoordinator, ScrapeCoordinator, WorkItem, WorkKind};
+use cors::CorsPolicy;
+use envelope::ApiVersion;
+use graphql::GraphQLRequest;
+use httpcache::HttpCache;
+use jobs::{JobQueue, ScrapeJob, ScrapeQueue};
+use models::{
+    highest_serial, match_expression, store_sticker_details, BrokenStickerRepository, Car,
+    CarHistoryRepository, CarId, CarModelRepository, CarQuery, CarRepository, CarStore,
+    ColorRepository, DealerRepository, FeedQuery, InsertOutcome, OptionRepository,
+    ReconcileQuery, SearchQuery, SearchRepository, SerialNumber, Vin,
 };
+use notify::watch::{NewWatch, WatchRepository, WatchStatus};
+use notify::webhooks::{send_due_deliveries, NewSubscription, SubscriptionRepository};
+use reqwest_wasm::header::{HeaderMap, HeaderValue};
+use routes::Routes;
+use scraper::leased::{drain, run_lease, DrainReport};
+use scraper::recheck::due_rechecks;
+use scraper::reparse::{reparse_stickers, ReparseQuery};
+use scraper::rescrape::rescrape;
+use scraper::vinlookup::{self, get_possible_vins_from_serial};
+use scraper::watched::due_watched;
+use stickers::{Sticker, StickerArchive, StickerQuery, StickerTextCache};
 use worker::*;
 
+mod auth;
 mod common;
+mod coordinator;
+mod cors;
+mod envelope;
+mod feed;
+mod graphql;
+mod httpcache;
+mod jobs;
 mod models;
+mod notify;
+mod openapi;
+mod ratelimit;
+mod routes;
 mod scraper;
+mod stickers;
 mod utils;
 
 fn log_request(req: &Request) {
@@ -25,19 +58,70 @@ fn log_request(req: &Request) {
 }
 
 #[event(fetch)]
-pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Response> {
+pub async fn main(req: Request, env: Env, ctx: worker::Context) -> Result<Response> {
     log_request(&req);
 
     utils::set_panic_hook();
 
-    let router = Router::new();
+    let cors = CorsPolicy::from_env(&env);
+    if req.method() == Method::Options {
+        return cors.preflight(&req);
+    }
+
+    // Both versions share the routes below, /v1 only changes how responses are shaped
+    let (req, version) = ApiVersion::split(req)?;
+    let response = match version {
+        ApiVersion::V1 => envelope::wrap(route(req.clone()?, env.clone()).await).await?,
+        ApiVersion::Unversioned => {
+            envelope::deprecate(&req, route(req.clone()?, env.clone()).await?)?
+        }
+    };
+    if notify::take_queued_deliveries() {
+        send_deliveries_later(&ctx, env);
+    }
+    cors.apply(&req, response)
+}
+
+async fn route(req: Request, env: Env) -> Result<Response> {
+    let client = match auth::authorize(&req, &env).await? {
+        Access::Denied(response) => return Ok(response),
+        access => access.client_key(&req),
+    };
+    if let Some(response) = ratelimit::check(&req, &env, &client).await? {
+        return Ok(response);
+    }
+
+    let cache = match httpcache::policy_for(&req) {
+        Some(policy) => match HttpCache::new(&req, &env, policy).await {
+            Ok(cache) => cache,
+            // Without the current generations a hit could be stale, so skip the cache
+            Err(e) => {
+                console_error!("couldn't read cache generations: {:?}", e);
+                return run_router(req, env).await;
+            }
+        },
+        None => return run_router(req, env).await,
+    };
+    if let Some(response) = cache.lookup(&req).await? {
+        return Ok(response);
+    }
+    let response = run_router(req.clone()?, env).await?;
+    cache.store(&req, response).await
+}
+
+async fn run_router(req: Request, env: Env) -> Result<Response> {
+    routes().run(req, env).await
+}
 
-    let response = router
+/// Every route the worker serves.
+fn routes() -> Routes<'static> {
+    Routes::default()
         .get("/", |_, _| Response::ok("Hello from Workers!"))
         .get("/worker-version", |_, ctx| {
             let version = ctx.var("WORKERS_RS_VERSION")?.to_string();
             Response::ok(version)
         })
+        .get("/openapi.json", |_, _| Response::from_json(&openapi::spec()))
         .get_async("/car/:id", |_, ctx| async move {
             let id = ctx.param("id").unwrap();
             match Car::from_d1(
@@ -47,7 +131,54 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
             .await
             {
                 Ok(car) => Response::from_json(&car),
-                Err(e) => Response::error(format!("No Car Found?: {:?}", e), 404),
+                Err(e) => {
+                    console_error!("couldn't load car {}: {:?}", id, e);
+                    Response::error("No Car Found", 404)
+                }
+            }
+        })
+        .get_async("/car/:id/options", |_, ctx| async move {
+            let id = match ctx.param("id").unwrap().parse::<i32>() {
+                Ok(id) => CarId(id),
+                Err(_) => return Response::error("Invalid CarId", 400),
+            };
+            let car = match Car::from_d1(id, &ctx).await? {
+                Some(car) => car,
+                None => return Response::error("No Car Found", 404),
+            };
+            let repo = OptionRepository::new(ctx.env.d1("failcat_db")?);
+            match repo.get_for_car(&car).await? {
+                Some(options) => Response::from_json(&options),
+                None => Response::error("No Car Found", 404),
+            }
+        })
+        .get_async("/car/:id/history", |_, ctx| async move {
+            let id = match ctx.param("id").unwrap().parse::<i32>() {
+                Ok(id) => CarId(id),
+                Err(_) => return Response::error("Invalid CarId", 400),
+            };
+            if Car::from_d1(id, &ctx).await?.is_none() {
+                return Response::error("No Car Found", 404);
+            }
+            let repo = CarHistoryRepository::new(ctx.env.d1("failcat_db")?);
+            let history = repo.get_for_car(id).await?;
+            Response::from_json(&history)
+        })
+        .post_async("/car/:id/rescrape", |_, ctx| async move {
+            let id = match ctx.param("id").unwrap().parse::<i32>() {
+                Ok(id) => CarId(id),
+                Err(_) => return Response::error("Invalid CarId", 400),
+            };
+            let car = match Car::from_d1(id, &ctx).await? {
+                Some(car) => car,
+                None => return Response::error("No Car Found", 404),
+            };
+            match rescrape(&ctx.env, &car).await {
+                Ok(report) => Response::from_json(&report),
+                Err(e) if vinlookup::is_limits_exceeded(&e) => {
+                    Response::error("limits exceeded downstream", 429)
+                }
+                Err(e) => Response::error(e.to_string(), 502),
             }
         })
         .get_async("/cars", |request, ctx| async move {
@@ -63,6 +194,12 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                 .await?;
             Response::from_json(&cars)
         })
+        .get_async("/feed.atom", |request, ctx| async move {
+            feed_response(request, &ctx, FeedFormat::Atom).await
+        })
+        .get_async("/feed.rss", |request, ctx| async move {
+            feed_response(request, &ctx, FeedFormat::Rss).await
+        })
         .get_async("/vinlookup/:vin", |_, ctx| async move {
             let vin = ctx.param("vin").unwrap();
             if !vinlookup::is_valid_vin(vin) {
@@ -82,37 +219,19 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                     .expect("could not parse serial"),
             );
             let vins = get_possible_vins_from_serial(&serial);
-            let bucket = ctx.bucket("pdf_bucket").unwrap();
+            let archive = StickerArchive::new(ctx.bucket("pdf_bucket")?);
+            let quarantine = BrokenStickerRepository::new(ctx.env.d1("failcat_db")?);
             for vin in vins {
-                let pdf = bucket.get(&vin).execute().await;
-                match pdf {
-                    Ok(None) => {
-                        match vinlookup::vinlookup(&vin).await {
-                            Ok(data) => {
-                                if data == b"SAP API limits exceeded" {
-                                    return Response::error("limits exceeded downstream", 429);
-                                }
-                                let stored = bucket.put(&vin, data.clone()).execute().await;
-                                match stored {
-                                    Ok(_) => println!("stored pdf"),
-                                    Err(_) => continue,
-                                }
-                                return Ok(Response::with_headers(
-                                    Response::from_bytes(data).expect("couldn't get bytes"),
-                                    file_pdf_headers(&vin).into(),
-                                ));
-                            }
-                            Err(_) => continue,
-                        };
-                    }
-                    Ok(Some(data)) => {
-                        let body = data.body().expect("couldn't get body");
-                        let bytes = body.bytes().await.expect("could not get bytes");
+                match archive.fetch(&vin, &quarantine).await {
+                    Ok(sticker) => {
                         return Ok(Response::with_headers(
-                            Response::from_bytes(bytes).expect("could not get bytes"),
+                            Response::from_bytes(sticker.bytes).expect("couldn't get bytes"),
                             file_pdf_headers(&vin).into(),
                         ));
                     }
+                    Err(e) if vinlookup::is_limits_exceeded(&e) => {
+                        return Response::error("limits exceeded downstream", 429);
+                    }
                     Err(_) => continue,
                 }
             }
@@ -121,54 +240,67 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
         })
         .post_async("/serial/:serial", |_, ctx| async move {
             let serial = ctx.param("serial").unwrap();
-            let car = Car::from_kv(SerialNumber::from(serial), &ctx).await;
-            match car {
-                Ok(Some(car)) => Response::error(format!("Car already saved.: {:?}", car), 409),
-                Err(e) => Response::error(format!("No Car Found?: {:?}", e), 404),
+            let store = CarStore::new(&ctx.env)?;
+            match store.get(SerialNumber::from(serial)).await {
+                Ok(Some(car)) => {
+                    let id = car.id.map(|id| id.to_string()).unwrap_or_default();
+                    Response::error(format!("Car already saved: {id}"), 409)
+                }
+                Err(e) => {
+                    console_error!("couldn't load serial {}: {:?}", serial, e);
+                    Response::error("No Car Found", 404)
+                }
                 Ok(None) => {
-                    let car = Car::from_vinlookup(serial.into(), &ctx)
+                    let car = Car::from_vinlookup(serial.into(), &ctx.env)
                         .await
                         .expect("couldn't find car");
                     match car {
-                        Some(car) => {
-                            let car_id = car
-                                .to_d1(ctx.env.d1("failcat_db")?)
-                                .await
-                                .expect("couldn't save car to database");
-                            let car = car
-                                .to_kv(&ctx, Some(car_id))
-                                .await
-                                .expect("couldn't save car to database");
-                            Response::from_json(&car)
-                        }
+                        Some(car) => match store.insert(&car).await? {
+                            InsertOutcome::Inserted(car_id) => {
+                                store_sticker_details(&ctx.env, car_id, &car.vin).await?;
+                                Response::from_json(&car_id)
+                            }
+                            InsertOutcome::Conflict(car_id) => {
+                                Response::error(format!("Car already saved: {car_id}"), 409)
+                            }
+                        },
                         None => Response::error("No Car Found", 404),
                     }
                 }
             }
         })
         .get_async("/scrape_next", |_, ctx| async move {
-            let next_serial_number = highest_serial(&ctx).await + 1.into();
-            match attempt_to_scrape_from_serial(next_serial_number, &ctx).await {
-                Ok(car_id) => Response::from_json(&car_id),
+            let coordinator = DurableCoordinator::new(&ctx.env)?;
+            let floor = highest_serial(&ctx.env).await + 1.into();
+            let lease = match coordinator.lease("scrape_next", floor).await? {
+                Some(lease) => lease,
+                None => {
+                    return Response::error("Scraping is paused, upstream limits were hit", 429)
+                }
+            };
+            match run_lease(&ctx.env, &coordinator, &lease).await {
+                Ok((_, car_id)) => Response::from_json(&car_id),
                 Err(e) => Response::error(e.to_string(), 500),
             }
         })
         .get_async("/scrape_next/:n", |_, ctx| async move {
             let num: SerialNumber = ctx.param("n").unwrap().into();
-            let next_serial_number = highest_serial(&ctx).await + num;
-            match attempt_to_scrape_from_serial(next_serial_number, &ctx).await {
+            let next_serial_number = highest_serial(&ctx.env).await + num;
+            let item = WorkItem::new(next_serial_number, WorkKind::Forward);
+            match scrape_claimed(&ctx.env, "scrape_next", item).await? {
                 Ok(car_id) => Response::from_json(&car_id),
-                Err(e) => Response::error(e.to_string(), 500),
+                Err(response) => Ok(response),
             }
         })
         .get_async("/scrape_below/:n", |_, ctx| async move {
             let num: SerialNumber = ctx.param("n").unwrap().into();
             let next_serial_number = Car::first_unknown_serial_below(&ctx, num).await?;
             if let Some(next_serial_number) = next_serial_number {
-                match attempt_to_scrape_from_serial(next_serial_number, &ctx).await {
+                let item = WorkItem::new(next_serial_number, WorkKind::Backfill);
+                match scrape_claimed(&ctx.env, "scrape_below", item).await? {
                     Ok(Some(car_id)) => Response::from_json(&ScrapeResponse::found(next_serial_number, car_id)),
                     Ok(None) => Response::from_json(&ScrapeResponse::not_found(next_serial_number)),
-                    Err(e) => Response::error(e.to_string(), 500),
+                    Err(response) => Ok(response),
                 }
             } else {
                 Response::error("No more cars to scrape", 404)
@@ -178,10 +310,11 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
             let num: SerialNumber = ctx.param("n").unwrap().into();
             let next_serial_number = Car::first_unknown_serial_above(&ctx, num).await?;
             if let Some(next_serial_number) = next_serial_number {
-                match attempt_to_scrape_from_serial(next_serial_number, &ctx).await {
+                let item = WorkItem::new(next_serial_number, WorkKind::Backfill);
+                match scrape_claimed(&ctx.env, "scrape_above", item).await? {
                     Ok(Some(car_id)) => Response::from_json(&ScrapeResponse::found(next_serial_number, car_id)),
                     Ok(None) => Response::from_json(&ScrapeResponse::not_found(next_serial_number)),
-                    Err(e) => Response::error(e.to_string(), 500),
+                    Err(response) => Ok(response),
                 }
             } else {
                 Response::error("No more cars to scrape", 404)
@@ -193,34 +326,336 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                 .unwrap()
                 .parse::<i32>()
                 .expect("couldn't parse serial number");
-            match attempt_to_scrape_from_serial(SerialNumber(serial_number), &ctx).await {
+            let item = WorkItem::new(SerialNumber(serial_number), WorkKind::Backfill);
+            match scrape_claimed(&ctx.env, "scrape", item).await? {
                 Ok(car_id) => Response::from_json(&car_id),
-                Err(e) => Response::error(e.to_string(), 500),
+                Err(response) => Ok(response),
             }
         })
+        .post_async("/scrape/jobs", |mut request, ctx| async move {
+            let jobs = match request.json::<Vec<ScrapeJob>>().await {
+                Ok(jobs) => jobs,
+                Err(e) => return Response::error(format!("Invalid scrape jobs: {e}"), 400),
+            };
+            match ScrapeQueue::new(&ctx.env) {
+                Ok(queue) => {
+                    let queued = jobs.len();
+                    queue.send(jobs).await?;
+                    Ok(Response::from_json(&queued)?.with_status(202))
+                }
+                Err(_) => Response::error("The scrape job queue isn't configured", 503),
+            }
+        })
+        .get_async("/admin/coordinator", |_, ctx| async move {
+            let coordinator = DurableCoordinator::new(&ctx.env)?;
+            Response::from_json(&coordinator.status().await?)
+        })
         .get_async("/window-sticker/:vin", |_, ctx| async move {
             let vin = ctx.param("vin").unwrap();
-            match vinlookup(vin).await {
-                Ok(data) => {
-                    if data == b"SAP API limits exceeded" {
-                        return Response::error("limits exceeded downstream", 429);
-                    }
-                    Ok(Response::with_headers(
-                        Response::from_bytes(data).expect("couldn't get bytes"),
-                        file_pdf_headers(vin).into(),
-                    ))
+            if !vinlookup::is_valid_vin(vin) {
+                return Response::error("Invalid VIN", 400);
+            }
+            let archive = StickerArchive::new(ctx.bucket("pdf_bucket")?);
+            let quarantine = BrokenStickerRepository::new(ctx.env.d1("failcat_db")?);
+            match archive.fetch(vin, &quarantine).await {
+                Ok(sticker) => Ok(Response::with_headers(
+                    Response::from_bytes(sticker.bytes).expect("couldn't get bytes"),
+                    file_pdf_headers(vin).into(),
+                )),
+                Err(e) if vinlookup::is_limits_exceeded(&e) => {
+                    Response::error("limits exceeded downstream", 429)
+                }
+                Err(e) => Response::error(e.to_string(), 500),
+            }
+        })
+        .get_async("/stickers", |request, ctx| async move {
+            let url = request.url()?;
+            let query_str = url.query().unwrap_or_default();
+            let sticker_query = match serde_qs::from_str::<StickerQuery>(query_str) {
+                Ok(sticker_query) => sticker_query,
+                Err(e) => return Response::error(format!("Invalid query: {e}"), 400),
+            };
+            let archive = StickerArchive::new(ctx.bucket("pdf_bucket")?);
+            let stickers = archive.list_for_serial(&sticker_query.serial).await?;
+            Response::from_json(&stickers)
+        })
+        .get_async("/stickers/:vin/text", |_, ctx| async move {
+            let vin = ctx.param("vin").unwrap();
+            let texts = StickerTextCache::new(ctx.env.d1("failcat_db")?);
+            if let Some(cached) = texts.get(vin).await? {
+                return Response::ok(cached.text);
+            }
+            let archive = StickerArchive::new(ctx.bucket("pdf_bucket")?);
+            match archive.get(vin).await? {
+                Some(sticker) => match texts.text_for(vin, &sticker.bytes).await {
+                    Ok(text) => Response::ok(text),
+                    Err(e) => Response::error(e.to_string(), 422),
+                },
+                None => Response::error("No Sticker Found", 404),
+            }
+        })
+        .get_async("/stickers/:vin", |request, ctx| async move {
+            let vin = ctx.param("vin").unwrap();
+            if !vinlookup::is_valid_vin(vin) {
+                return Response::error("Invalid VIN", 400);
+            }
+            let archive = StickerArchive::new(ctx.bucket("pdf_bucket")?);
+            let quarantine = BrokenStickerRepository::new(ctx.env.d1("failcat_db")?);
+            match archive.fetch(vin, &quarantine).await {
+                Ok(sticker) => sticker_response(&request, sticker),
+                Err(e) if vinlookup::is_limits_exceeded(&e) => {
+                    Response::error("limits exceeded downstream", 429)
                 }
                 Err(e) => Response::error(e.to_string(), 500),
             }
         })
+        .post_async("/graphql", |mut request, ctx| async move {
+            let query = match request.json::<GraphQLRequest>().await {
+                Ok(query) => query,
+                Err(e) => return Response::error(format!("Invalid GraphQL request: {e}"), 400),
+            };
+            let response = graphql::schema(ctx.env).execute(query).await;
+            Response::from_json(&response)
+        })
+        .get_async("/search", |request, ctx| async move {
+            let url = request.url()?;
+            let query_str = url.query().unwrap_or_default();
+            let search_query = match serde_qs::from_str::<SearchQuery>(query_str) {
+                Ok(search_query) => search_query,
+                Err(e) => return Response::error(format!("Invalid query: {e}"), 400),
+            };
+            let expression = match match_expression(&search_query.q) {
+                Some(expression) => expression,
+                None => return Response::error("Search terms need at least 3 characters", 400),
+            };
+            let repo = SearchRepository::new(ctx.env.d1("failcat_db")?);
+            let results = repo.search(&expression, search_query.limit).await?;
+            Response::from_json(&results)
+        })
         .get_async("/dealers", |_, ctx| async move {
             let repo = DealerRepository::new(ctx.env.d1("failcat_db")?);
             let dealers = repo.get_all().await.expect("couldn't get dealers");
             Response::from_json(&dealers)
         })
-        .run(req.clone()?, env)
-        .await?;
-    Ok(handle_cors(&req, response))
+        .post_async("/admin/reparse", |request, ctx| async move {
+            let url = request.url()?;
+            let query_str = url.query().unwrap_or_default();
+            let reparse_query = match serde_qs::from_str::<ReparseQuery>(query_str) {
+                Ok(reparse_query) => reparse_query,
+                Err(e) => return Response::error(format!("Invalid query: {e}"), 400),
+            };
+            let report = reparse_stickers(&ctx.env, reparse_query).await?;
+            Response::from_json(&report)
+        })
+        .post_async("/admin/reconcile", |request, ctx| async move {
+            let url = request.url()?;
+            let query_str = url.query().unwrap_or_default();
+            let reconcile_query = match serde_qs::from_str::<ReconcileQuery>(query_str) {
+                Ok(reconcile_query) => reconcile_query,
+                Err(e) => return Response::error(format!("Invalid query: {e}"), 400),
+            };
+            let store = CarStore::new(&ctx.env)?;
+            let report = store.reconcile(reconcile_query).await?;
+            Response::from_json(&report)
+        })
+        .post_async("/keys", |mut request, ctx| async move {
+            let new_key = match request.json::<NewApiKey>().await {
+                Ok(new_key) => new_key,
+                Err(e) => return Response::error(format!("Invalid api key: {e}"), 400),
+            };
+            if new_key.scopes.is_empty() {
+                return Response::error("An api key needs at least one scope", 400);
+            }
+            let repo = ApiKeyRepository::new(ctx.env.d1("failcat_db")?);
+            let created = repo.create(new_key).await?;
+            Ok(Response::from_json(&created)?.with_status(201))
+        })
+        .get_async("/keys", |_, ctx| async move {
+            let repo = ApiKeyRepository::new(ctx.env.d1("failcat_db")?);
+            let keys = repo.get_all().await?;
+            Response::from_json(&keys)
+        })
+        .delete_async("/keys/:id", |_, ctx| async move {
+            let id = match ctx.param("id").unwrap().parse::<i32>() {
+                Ok(id) => id,
+                Err(_) => return Response::error("Invalid api key id", 400),
+            };
+            let repo = ApiKeyRepository::new(ctx.env.d1("failcat_db")?);
+            match repo.revoke(id).await? {
+                Some(key) => Response::from_json(&key),
+                None => Response::error("No Api Key Found", 404),
+            }
+        })
+        .get_async("/keys/:id/usage", |_, ctx| async move {
+            let id = match ctx.param("id").unwrap().parse::<i32>() {
+                Ok(id) => id,
+                Err(_) => return Response::error("Invalid api key id", 400),
+            };
+            let repo = ApiKeyRepository::new(ctx.env.d1("failcat_db")?);
+            let usage = repo.usage(id).await?;
+            Response::from_json(&usage)
+        })
+        .get_async("/colors", |_, ctx| async move {
+            let repo = ColorRepository::new(ctx.env.d1("failcat_db")?);
+            let colors = repo.get_all().await?;
+            Response::from_json(&colors)
+        })
+        .get_async("/models", |_, ctx| async move {
+            let repo = CarModelRepository::new(ctx.env.d1("failcat_db")?);
+            let models = repo.get_all().await?;
+            Response::from_json(&models)
+        })
+        .post_async("/subscriptions", |mut request, ctx| async move {
+            let new_subscription = match request.json::<NewSubscription>().await {
+                Ok(new_subscription) => new_subscription,
+                Err(e) => return Response::error(format!("Invalid subscription: {e}"), 400),
+            };
+            if !new_subscription.url.starts_with("https://")
+                && !new_subscription.url.starts_with("http://")
+            {
+                return Response::error("Webhook url must be http(s)", 400);
+            }
+            if let Err(e) = new_subscription.filter.validate() {
+                return Response::error(format!("Invalid subscription: {e}"), 400);
+            }
+            let repo = SubscriptionRepository::new(ctx.env.d1("failcat_db")?);
+            let subscription = repo.create(new_subscription).await?;
+            Ok(Response::from_json(&subscription)?.with_status(201))
+        })
+        .get_async("/subscriptions/:id/deliveries", |_, ctx| async move {
+            let id = match ctx.param("id").unwrap().parse::<i32>() {
+                Ok(id) => id,
+                Err(_) => return Response::error("Invalid subscription id", 400),
+            };
+            let repo = SubscriptionRepository::new(ctx.env.d1("failcat_db")?);
+            let deliveries = repo.get_deliveries(id).await?;
+            Response::from_json(&deliveries)
+        })
+        .post_async("/watch", |mut request, ctx| async move {
+            let mut new_watch = match request.json::<NewWatch>().await {
+                Ok(new_watch) => new_watch,
+                Err(e) => return Response::error(format!("Invalid watch: {e}"), 400),
+            };
+            if new_watch.webhook_url.is_none() && new_watch.email.is_none() {
+                return Response::error("A webhook_url or email is required", 400);
+            }
+            if let Some(url) = &new_watch.webhook_url {
+                if !url.starts_with("https://") && !url.starts_with("http://") {
+                    return Response::error("Webhook url must be http(s)", 400);
+                }
+            }
+            let serial = match (&new_watch.vin, new_watch.serial_number) {
+                (Some(vin), _) => {
+                    let vin = Vin(vin.to_uppercase());
+                    if !vinlookup::is_valid_vin(&vin) {
+                        return Response::error("Invalid VIN", 400);
+                    }
+                    let serial = SerialNumber::from(vin.clone());
+                    new_watch.vin = Some(vin);
+                    serial
+                }
+                (None, Some(serial)) => serial,
+                (None, None) => return Response::error("A vin or serial_number is required", 400),
+            };
+            let repo = WatchRepository::new(ctx.env.d1("failcat_db")?);
+            let watch = repo.create(serial, new_watch).await?;
+            Ok(Response::from_json(&watch)?.with_status(201))
+        })
+        .get_async("/watch/:id", |_, ctx| async move {
+            let id = match ctx.param("id").unwrap().parse::<i32>() {
+                Ok(id) => id,
+                Err(_) => return Response::error("Invalid watch id", 400),
+            };
+            let repo = WatchRepository::new(ctx.env.d1("failcat_db")?);
+            match repo.get(id).await? {
+                Some(watch) => Response::from_json(&WatchStatus::from(watch)),
+                None => Response::error("No Watch Found", 404),
+            }
+        })
+        .get_async("/broken", |_, ctx| async move {
+            let repo = BrokenStickerRepository::new(ctx.env.d1("failcat_db")?);
+            let broken = repo.get_unresolved().await?;
+            Response::from_json(&broken)
+        })
+        .get_async("/dealers/:code/stats", |_, ctx| async move {
+            let code = ctx.param("code").unwrap();
+            let repo = DealerRepository::new(ctx.env.d1("failcat_db")?);
+            match repo.stats(code).await? {
+                Some(stats) => Response::from_json(&stats),
+                None => Response::error("No Dealer Found", 404),
+            }
+        })
+}
+
+/// Scrapes one specific serial through the coordinator, so a manual trigger never fetches a
+/// serial some other worker already has in flight. Hands back the error response otherwise.
+async fn scrape_claimed(
+    env: &Env,
+    worker: &str,
+    item: WorkItem,
+) -> Result<std::result::Result<Option<CarId>, Response>> {
+    let coordinator = DurableCoordinator::new(env)?;
+    let lease = match coordinator.claim(worker, item).await? {
+        Some(lease) => lease,
+        None => return Ok(Err(Response::error("Serial is already being scraped", 409)?)),
+    };
+    match run_lease(env, &coordinator, &lease).await {
+        Ok((_, car_id)) => Ok(Ok(car_id)),
+        Err(e) => Ok(Err(Response::error(e.to_string(), 500)?)),
+    }
+}
+
+// Watched serials and quarantined rechecks queued per cron trigger
+const WATCHED_BATCH_SIZE: i32 = 10;
+const RECHECK_BATCH_SIZE: i32 = 10;
+// Work run per cron trigger, kept small to stay under the upstream limits
+const SCHEDULED_BATCH_SIZE: i32 = 20;
+
+#[event(scheduled)]
+pub async fn scheduled(_event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    utils::set_panic_hook();
+
+    match run_scheduled_scrapes(&env).await {
+        Ok(report) => console_log!("scheduled scrapes finished: {:?}", report),
+        Err(e) => console_error!("scheduled scrapes failed: {:?}", e),
+    }
+
+    match send_due_deliveries(&env).await {
+        Ok(sent) => console_log!("sent {} webhook deliveries", sent),
+        Err(e) => console_error!("webhook deliveries failed: {:?}", e),
+    }
+}
+
+/// Sends the webhook deliveries a request or batch queued once its response is out of the way.
+fn send_deliveries_later(ctx: &worker::Context, env: Env) {
+    ctx.wait_until(async move {
+        if let Err(e) = send_due_deliveries(&env).await {
+            console_error!("webhook deliveries failed: {:?}", e);
+        }
+    });
+}
+
+#[event(queue)]
+pub async fn consume_scrape_jobs(
+    batch: MessageBatch<ScrapeJob>,
+    env: Env,
+    ctx: worker::Context,
+) -> Result<()> {
+    utils::set_panic_hook();
+    let result = jobs::queue::consume(batch, &env).await;
+    if notify::take_queued_deliveries() {
+        send_deliveries_later(&ctx, env);
+    }
+    result
+}
+
+/// Queues watched serials and due rechecks with the coordinator, then works through the queue
+/// ahead of the forward cursor.
+async fn run_scheduled_scrapes(env: &Env) -> Result<DrainReport> {
+    let coordinator = DurableCoordinator::new(env)?;
+    coordinator.enqueue(due_watched(env, WATCHED_BATCH_SIZE).await?).await?;
+    coordinator.enqueue(due_rechecks(env, RECHECK_BATCH_SIZE).await?).await?;
+    drain(env, &coordinator, "cron", SCHEDULED_BATCH_SIZE).await
 }
 
 fn file_pdf_headers(vin: &str) -> HeaderMap {
@@ -236,28 +671,75 @@ fn file_pdf_headers(vin: &str) -> HeaderMap {
     headers
 }
 
-fn handle_cors(req: &Request, res: Response) -> Response {
-    let origin = req
-        .headers()
-        .get("Origin")
-        .unwrap_or_default()
-        .unwrap_or_default();
-    if origin.contains("vteng.io") || origin.contains("localhost") {
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            "Access-Control-Allow-Origin",
-            HeaderValue::from_str(&origin).unwrap(),
-        );
-        headers.insert(
-            "Access-Control-Allow-Methods",
-            HeaderValue::from_static("GET, POST, OPTIONS"),
-        );
-        headers.insert(
-            "Access-Control-Allow-Headers",
-            HeaderValue::from_static("Content-Type"),
-        );
-        res.with_headers(headers.into())
-    } else {
-        res
+enum FeedFormat {
+    Atom,
+    Rss,
+}
+
+async fn feed_response(
+    req: Request,
+    ctx: &RouteContext<()>,
+    format: FeedFormat,
+) -> Result<Response> {
+    let url = req.url()?;
+    let feed_query = match serde_qs::from_str::<FeedQuery>(url.query().unwrap_or_default()) {
+        Ok(feed_query) => feed_query,
+        Err(e) => return Response::error(format!("Invalid query: {e}"), 400),
+    };
+    let cars = CarRepository::new(ctx.env.d1("failcat_db")?)
+        .get_recent(&feed_query)
+        .await?;
+
+    let base_url = url.origin().ascii_serialization();
+    let (body, content_type) = match format {
+        FeedFormat::Atom => (feed::atom(&cars, url.as_str(), &base_url), "application/atom+xml"),
+        FeedFormat::Rss => (feed::rss(&cars, url.as_str(), &base_url), "application/rss+xml"),
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", HeaderValue::from_static(content_type));
+    Ok(Response::with_headers(Response::ok(body)?, headers.into()))
+}
+
+fn sticker_response(req: &Request, sticker: Sticker) -> Result<Response> {
+    let info = &sticker.info;
+    let last_modified = info
+        .last_modified()
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string();
+
+    let if_none_match = req.headers().get("If-None-Match")?;
+    let if_modified_since = req.headers().get("If-Modified-Since")?;
+    let not_modified = match (if_none_match, if_modified_since) {
+        (Some(etags), _) => etags
+            .split(',')
+            .any(|etag| etag.trim() == info.etag || etag.trim() == "*"),
+        (None, Some(since)) => DateTime::parse_from_rfc2822(&since)
+            .map(|since| info.last_modified().timestamp() <= since.timestamp())
+            .unwrap_or(false),
+        (None, None) => false,
+    };
+
+    let mut headers = file_pdf_headers(&info.vin);
+    headers.insert(
+        "ETag",
+        HeaderValue::from_str(&info.etag).expect("couldn't set header"),
+    );
+    headers.insert(
+        "Last-Modified",
+        HeaderValue::from_str(&last_modified).expect("couldn't set header"),
+    );
+    headers.insert(
+        "Cache-Control",
+        HeaderValue::from_static("public, max-age=86400"),
+    );
+
+    if not_modified {
+        return Ok(Response::empty()?
+            .with_status(304)
+            .with_headers(headers.into()));
     }
+    Ok(Response::with_headers(
+        Response::from_bytes(sticker.bytes)?,
+        headers.into(),
+    ))
 }
@@ -0,0 +1,118 @@
+use chrono::Utc;
+use derive_more::Display;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use super::*;
+
+// After this many failed rechecks a pending car is marked broken for good
+pub const MAX_RECHECK_ATTEMPTS: i32 = 48;
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Display)]
+#[serde(rename_all = "snake_case")]
+pub enum BrokenReason {
+    #[display(fmt = "empty_response")]
+    EmptyResponse,
+    #[display(fmt = "short_response")]
+    ShortResponse,
+}
+
+impl BrokenReason {
+    pub fn from_body(body: &[u8]) -> Self {
+        if body.iter().all(|b| b.is_ascii_whitespace()) {
+            BrokenReason::EmptyResponse
+        } else {
+            BrokenReason::ShortResponse
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct BrokenSticker {
+    pub id: Option<i32>,
+    pub serial_number: SerialNumber,
+    pub vin: Vin,
+    pub reason: BrokenReason,
+    pub raw_body: String,
+    pub attempts: i32,
+    pub first_seen: String,
+    pub last_checked: String,
+    pub resolved_at: Option<String>,
+}
+
+pub struct BrokenStickerRepository {
+    d1: Database,
+}
+
+impl BrokenStickerRepository {
+    pub fn new(d1: Database) -> Self {
+        BrokenStickerRepository { d1 }
+    }
+
+    /// Records a broken upstream response for `serial`, bumping the attempt count if it is already quarantined.
+    pub async fn quarantine(
+        &self,
+        serial_number: SerialNumber,
+        vin: &str,
+        body: &[u8],
+    ) -> worker::Result<()> {
+        let now = Utc::now().to_rfc3339();
+        let statement = self.d1.prepare(
+            "INSERT INTO broken_stickers (serial_number, vin, reason, raw_body, attempts, first_seen, last_checked) VALUES (?, ?, ?, ?, 1, ?, ?)
+            ON CONFLICT (serial_number) DO UPDATE SET vin = excluded.vin, reason = excluded.reason, raw_body = excluded.raw_body, attempts = attempts + 1, last_checked = excluded.last_checked, resolved_at = NULL",
+        );
+        let query = statement.bind(&[
+            serial_number.0.into(),
+            vin.into(),
+            BrokenReason::from_body(body).to_string().into(),
+            String::from_utf8_lossy(body).to_string().into(),
+            now.clone().into(),
+            now.into(),
+        ])?;
+        query.run().await?;
+        Ok(())
+    }
+
+    pub async fn get(&self, serial_number: SerialNumber) -> worker::Result<Option<BrokenSticker>> {
+        let statement = self
+            .d1
+            .prepare("SELECT * FROM broken_stickers WHERE serial_number = ?");
+        let query = statement.bind(&[serial_number.0.into()])?;
+        query.first::<BrokenSticker>(None).await
+    }
+
+    pub async fn get_unresolved(&self) -> worker::Result<Vec<BrokenSticker>> {
+        let statement = self.d1.prepare(
+            "SELECT * FROM broken_stickers WHERE resolved_at IS NULL ORDER BY serial_number DESC",
+        );
+        statement.all().await?.results::<BrokenSticker>()
+    }
+
+    /// Quarantined serials still worth retrying, least recently checked first.
+    pub async fn due_for_recheck(&self, limit: i32) -> worker::Result<Vec<BrokenSticker>> {
+        let statement = self.d1.prepare(
+            "SELECT * FROM broken_stickers WHERE resolved_at IS NULL AND attempts < ? ORDER BY last_checked ASC LIMIT ?",
+        );
+        let query = statement.bind(&[MAX_RECHECK_ATTEMPTS.into(), limit.into()])?;
+        query.all().await?.results::<BrokenSticker>()
+    }
+
+    pub async fn record_attempt(&self, serial_number: SerialNumber) -> worker::Result<()> {
+        let statement = self.d1.prepare(
+            "UPDATE broken_stickers SET attempts = attempts + 1, last_checked = ? WHERE serial_number = ?",
+        );
+        let query = statement.bind(&[Utc::now().to_rfc3339().into(), serial_number.0.into()])?;
+        query.run().await?;
+        Ok(())
+    }
+
+    pub async fn resolve(&self, serial_number: SerialNumber) -> worker::Result<()> {
+        let statement = self
+            .d1
+            .prepare("UPDATE broken_stickers SET resolved_at = ? WHERE serial_number = ?");
+        let query = statement.bind(&[Utc::now().to_rfc3339().into(), serial_number.0.into()])?;
+        query.run().await?;
+        Ok(())
+    }
+}
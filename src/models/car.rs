@@ -1,11 +1,38 @@
 use crate::{scraper::vinlookup::{self, get_possible_vins_from_serial, VinYear}, common::deserialize_string_to_datetime};
+use crate::stickers::{
+    extract_sticker_text, ParseStatus, StickerArchive, StickerTextCache, BROKEN_STICKER_SIZE,
+};
 use chrono::{DateTime, Utc};
 use worker::wasm_bindgen::JsValue; // Add Fixed to imports
 
 use super::*;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, PartialOrd)]
+// Bump whenever `Car::from_sticker_text` changes so `/admin/reparse` can tell which rows are stale
+pub const PARSER_VERSION: i32 = 5;
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, PartialOrd, Default, Display)]
+#[serde(rename_all = "lowercase")]
+pub enum CarStatus {
+    #[default]
+    #[display(fmt = "ok")]
+    Ok,
+    #[display(fmt = "broken")]
+    Broken,
+    #[display(fmt = "pending")]
+    Pending,
+}
+
+/// What happened to a car written with `Car::to_d1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertOutcome {
+    Inserted(CarId),
+    /// Another row already had this serial or VIN, usually a concurrent scrape that got there first.
+    Conflict(CarId),
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, PartialEq, PartialOrd)]
 pub struct Car {
     pub id: Option<CarId>,
     pub vin: Vin,
@@ -19,6 +46,23 @@ pub struct Car {
     pub created_date: DateTime<Utc>,
     pub serial_number: SerialNumber,
     pub model_year: String,
+    #[serde(default)]
+    pub status: CarStatus,
+    #[serde(default)]
+    pub parser_version: i32,
+    #[serde(default)]
+    pub model_code: Option<String>,
+    #[serde(default)]
+    pub ext_color_name: Option<String>,
+    #[serde(default)]
+    pub ext_paint_code: Option<String>,
+    #[serde(default)]
+    pub int_color_name: Option<String>,
+    #[serde(default)]
+    pub int_material: Option<String>,
+    /// Bumped on every D1 update so a cached copy in KV can tell it's stale.
+    #[serde(default)]
+    pub version: i32,
 }
 
 impl Car {
@@ -36,7 +80,7 @@ impl Car {
         _dead_until: Option<String>,
         _last_attempt: Option<String>,
     ) -> Self {
-        Self {
+        let mut car = Self {
             id: None,
             vin,
             ext_color,
@@ -48,6 +92,42 @@ impl Car {
             created_date: Utc::now(),
             serial_number,
             model_year,
+            status: CarStatus::Ok,
+            parser_version: PARSER_VERSION,
+            model_code: None,
+            ext_color_name: None,
+            ext_paint_code: None,
+            int_color_name: None,
+            int_material: None,
+            version: 0,
+        };
+        car.normalize_colors();
+        car
+    }
+
+    /// Stand-in row for a serial whose sticker upstream only returns broken responses, so the
+    /// scraper still treats the serial as known.
+    pub fn placeholder(vin: Vin, serial_number: SerialNumber, status: CarStatus) -> Self {
+        Self {
+            id: None,
+            vin,
+            ext_color: String::new(),
+            int_color: String::new(),
+            car_model: String::new(),
+            opt_code: String::new(),
+            ship_to: String::new(),
+            sold_to: String::new(),
+            created_date: Utc::now(),
+            serial_number,
+            model_year: VinYear::from_serial(serial_number).year.to_string(),
+            status,
+            parser_version: PARSER_VERSION,
+            model_code: None,
+            ext_color_name: None,
+            ext_paint_code: None,
+            int_color_name: None,
+            int_material: None,
+            version: 0,
         }
     }
 
@@ -55,6 +135,62 @@ impl Car {
         self.id = Some(id);
     }
 
+    /// Fills the canonical color fields from the raw sticker strings, which are kept as is.
+    pub fn normalize_colors(&mut self) {
+        let exterior = ExteriorColor::from_sticker(&self.ext_color);
+        let interior = InteriorColor::from_sticker(&self.int_color);
+        self.ext_color_name = Some(exterior.name);
+        self.ext_paint_code = exterior.paint_code;
+        self.int_color_name = Some(interior.name);
+        self.int_material = interior.material;
+    }
+
+    pub fn car_model_entry(&self) -> Option<CarModel> {
+        self.model_code
+            .as_ref()
+            .map(|model_code| CarModel::new(model_code.clone(), self.car_model.clone()))
+    }
+
+    fn sticker_fields(&self) -> [(&'static str, Option<String>); 13] {
+        [
+            ("vin", Some(self.vin.0.clone())),
+            ("ext_color", Some(self.ext_color.clone())),
+            ("int_color", Some(self.int_color.clone())),
+            ("car_model", Some(self.car_model.clone())),
+            ("opt_code", Some(self.opt_code.clone())),
+            ("ship_to", Some(self.ship_to.clone())),
+            ("sold_to", Some(self.sold_to.clone())),
+            ("model_year", Some(self.model_year.clone())),
+            ("model_code", self.model_code.clone()),
+            ("ext_color_name", self.ext_color_name.clone()),
+            ("ext_paint_code", self.ext_paint_code.clone()),
+            ("int_color_name", self.int_color_name.clone()),
+            ("int_material", self.int_material.clone()),
+        ]
+    }
+
+    /// Old and new values of the sticker fields that differ between two parses of the same car.
+    pub fn changes(&self, other: &Car) -> Vec<FieldChange> {
+        self.sticker_fields()
+            .into_iter()
+            .zip(other.sticker_fields())
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((field, old_value), (_, new_value))| FieldChange {
+                field,
+                old_value,
+                new_value,
+            })
+            .collect()
+    }
+
+    /// Names of the sticker fields that differ between two parses of the same car.
+    pub fn diff(&self, other: &Car) -> Vec<&'static str> {
+        self.changes(other)
+            .into_iter()
+            .map(|change| change.field)
+            .collect()
+    }
+
     pub async fn from_d1(id: CarId, ctx: &RouteContext<()>) -> worker::Result<Option<Car>> {
         let d1 = ctx.env.d1("failcat_db").expect("Couldn't get db");
         let statement = d1.prepare("SELECT * FROM cars WHERE id = ?");
@@ -92,15 +228,24 @@ impl Car {
         }
     }
 
-    pub async fn to_d1(&self, d1: Database) -> worker::Result<CarId> {
-        let serial_number = self.serial_number;
-        let maybe_car = Car::from_d1_serial(serial_number, &d1).await?;
-        if let Some(car) = maybe_car {
-            return Ok(car);
+    pub async fn from_d1_by_serial(
+        serial_number: SerialNumber,
+        d1: &Database,
+    ) -> worker::Result<Option<Car>> {
+        let statement = d1.prepare("SELECT * FROM cars WHERE serial_number = ?");
+        let query = statement.bind(&[serial_number.0.into()])?;
+        query.first::<Car>(None).await
+    }
+
+    /// Inserts the car unless a row with the same serial or VIN already exists, in which case
+    /// the existing row is left alone and its id comes back as a `Conflict`. Safe to race.
+    pub async fn to_d1(&self, d1: &Database) -> worker::Result<InsertOutcome> {
+        if let Some(car_model) = self.car_model_entry() {
+            car_model.to_d1(d1).await?;
         }
 
         let statement = d1.prepare(
-            "INSERT INTO cars (vin, ext_color, int_color, car_model, opt_code, ship_to, sold_to, created_date, serial_number, model_year, dead_until, last_attempt) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+            "INSERT INTO cars (vin, ext_color, int_color, car_model, opt_code, ship_to, sold_to, created_date, serial_number, model_year, dead_until, last_attempt, status, parser_version, model_code, ext_color_name, ext_paint_code, int_color_name, int_material) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT DO NOTHING RETURNING id;",
         );
 
         let created_date = self
@@ -109,7 +254,7 @@ impl Car {
             .format("%Y-%m-%d %H:%M:%S%.6f")
             .to_string();
 
-        let bind_list: [JsValue; 12] = [
+        let bind_list: [JsValue; 19] = [
             self.vin.0.clone().into(),
             self.ext_color.clone().into(),
             self.int_color.clone().into(),
@@ -122,83 +267,75 @@ impl Car {
             self.model_year.clone().into(),
             Utc::now().to_string().into(),
             Utc::now().to_string().into(),
+            self.status.to_string().into(),
+            self.parser_version.into(),
+            self.model_code.clone().into(),
+            self.ext_color_name.clone().into(),
+            self.ext_paint_code.clone().into(),
+            self.int_color_name.clone().into(),
+            self.int_material.clone().into(),
         ];
-
-        let maybe_statement = statement.bind(&bind_list);
         console_debug!("bind list {:?}", bind_list);
 
-        match maybe_statement {
-            Ok(statement) => {
-                match statement.first::<()>(None).await {
-                    Ok(None) => {
-                        let car_id = Car::from_d1_serial(self.serial_number, &d1)
-                            .await?
-                            .expect("Couldn't find car we just saved");
-                        Ok(car_id)
-                    }
-                    Ok(Some(something)) => Err(format!(
-                        "\n\nError inserting car into db unexpected: {:?}",
-                        something
-                    )
-                    .into()),
-                    Err(e) => Err(format!("\n\nError inserting car into db: {:?}", e).into()),
-                }
-            }
-            Err(e) => {
-                console_debug!("\n\nActually binding failed with: {:?}", e);
-                Err(e)
-            }
+        let query = statement.bind(&bind_list)?;
+        if let Some(car_id) = query.first::<i32>(Some("id")).await? {
+            return Ok(InsertOutcome::Inserted(CarId(car_id)));
         }
-    }
 
-    pub async fn from_kv(
-        serial: SerialNumber,
-        ctx: &RouteContext<()>,
-    ) -> worker::Result<Option<Car>> {
-        let kv = ctx.env.kv("vinscrapes").expect("Couldn't get db");
-        let response = kv.get(&serial.to_string()).json().await;
-        match response {
-            Ok(data) => {
-                let result = data;
-                Ok(result)
-            }
-            Err(_) => Ok(None),
+        let statement = d1.prepare("SELECT id FROM cars WHERE serial_number = ? OR vin = ?");
+        let query = statement.bind(&[self.serial_number.0.into(), self.vin.0.clone().into()])?;
+        match query.first::<i32>(Some("id")).await? {
+            Some(car_id) => Ok(InsertOutcome::Conflict(CarId(car_id))),
+            None => Err(format!("Insert of {} skipped without a conflicting row", self.serial_number)
+                .into()),
         }
     }
 
-    // Add a car to the KV store
-    pub async fn to_kv(
-        &self,
-        ctx: &RouteContext<()>,
-        sql_id: Option<CarId>,
-    ) -> worker::Result<&CarId> {
-        if sql_id.is_none() {
-            panic!("No SQL ID");
-        }
-
-        let car_id = match &self.id {
-            Some(db_id) => db_id,
-            _ => panic!("No SQL ID or doesn't match"),
-        };
+    /// The statement overwriting the stored row for this serial, used when a placeholder finally
+    /// gets a real sticker. Left to the caller to run so it can share a batch with the history rows.
+    pub fn replace_statement(&self, d1: &Database) -> worker::Result<D1PreparedStatement> {
+        let statement = d1.prepare(
+            "UPDATE cars SET vin = ?, ext_color = ?, int_color = ?, car_model = ?, opt_code = ?, ship_to = ?, sold_to = ?, model_year = ?, last_attempt = ?, status = ?, parser_version = ?, model_code = ?, ext_color_name = ?, ext_paint_code = ?, int_color_name = ?, int_material = ?, version = version + 1 WHERE serial_number = ?",
+        );
+        statement.bind(&[
+            self.vin.0.clone().into(),
+            self.ext_color.clone().into(),
+            self.int_color.clone().into(),
+            self.car_model.clone().into(),
+            self.opt_code.clone().into(),
+            self.ship_to.clone().into(),
+            self.sold_to.clone().into(),
+            self.model_year.clone().into(),
+            Utc::now().to_string().into(),
+            self.status.to_string().into(),
+            self.parser_version.into(),
+            self.model_code.clone().into(),
+            self.ext_color_name.clone().into(),
+            self.ext_paint_code.clone().into(),
+            self.int_color_name.clone().into(),
+            self.int_material.clone().into(),
+            self.serial_number.0.into(),
+        ])
+    }
 
-        if sql_id.unwrap() != *car_id {
-            panic!("No SQL IDs Don't match");
-        }
+    pub async fn set_status_d1(
+        serial_number: SerialNumber,
+        status: CarStatus,
+        d1: &Database,
+    ) -> worker::Result<()> {
+        let statement =
+            d1.prepare("UPDATE cars SET status = ?, version = version + 1 WHERE serial_number = ?");
+        let query = statement.bind(&[status.to_string().into(), serial_number.0.into()])?;
+        query.run().await?;
+        Ok(())
+    }
 
-        let kv = ctx.env.kv("vinscrapes").expect("Couldn't get db");
-        let response = kv
-            .put(&self.serial_number.to_string(), self)
-            .expect("Couldn't build put options")
-            .execute()
-            .await;
-        match response {
-            Ok(_) => Ok(car_id),
-            Err(_) => Err("Failed to save to kv".into()),
-        }
+    pub fn from_pdf(pdf_bytes: Vec<u8>) -> worker::Result<Option<Car>> {
+        let pdf_text = extract_sticker_text(&pdf_bytes)?;
+        Car::from_sticker_text(&pdf_text)
     }
 
-    pub async fn from_pdf(pdf_bytes: Vec<u8>) -> worker::Result<Option<Car>> {
-        let pdf_text = pdf_extract::extract_text_from_mem(&pdf_bytes).expect("Couldn't parse pdf");
+    pub fn from_sticker_text(pdf_text: &str) -> worker::Result<Option<Car>> {
         let model = "MODEL/OPT.CODE";
         let ext_color = "EXTERIOR COLOR";
         let int_color = "INTERIOR COLOR";
@@ -219,7 +356,7 @@ impl Car {
             .split('/')
             .map(|s| s.trim())
             .collect();
-        let _model_code = vin_code.first().unwrap_or(&"").to_string();
+        let model_code = vin_code.first().unwrap_or(&"").to_string();
         let opt_code = vin_code.get(1).unwrap_or(&"").to_string();
         let ext_color_value = pdf_text[ext_color_index + ext_color.len() + 1..int_color_index]
             .trim()
@@ -240,7 +377,7 @@ impl Car {
         let dealer_address = sold_to_value.replace(&ship_to_value, "").trim().to_string();
         let _zip = dealer_address[dealer_address.len() - 5..].to_string();
         let serial_number : SerialNumber = Vin(vin_value.clone()).into();
-        let car = Car {
+        let mut car = Car {
             id: None,
             vin: Vin(vin_value),
             ext_color: ext_color_value,
@@ -252,73 +389,67 @@ impl Car {
             created_date: Utc::now(),
             serial_number,
             model_year: VinYear::from_serial(serial_number).year.to_string(),
+            status: CarStatus::Ok,
+            parser_version: PARSER_VERSION,
+            model_code: Some(model_code).filter(|model_code| !model_code.is_empty()),
+            ext_color_name: None,
+            ext_paint_code: None,
+            int_color_name: None,
+            int_material: None,
+            version: 0,
         };
+        car.normalize_colors();
         Ok(Some(car))
     }
 
-    pub async fn from_vinlookup(
-        serial: SerialNumber,
-        ctx: &RouteContext<()>,
-    ) -> Result<Option<Car>> {
+    pub async fn from_vinlookup(serial: SerialNumber, env: &Env) -> Result<Option<Car>> {
         console_debug!("Looking up {} in 'vinlookup'", serial);
         let vins = get_possible_vins_from_serial(&serial);
-        let bucket = ctx.bucket("pdf_bucket").unwrap();
+        let archive = StickerArchive::new(env.bucket("pdf_bucket")?);
+        let texts = StickerTextCache::new(env.d1("failcat_db")?);
+        let mut broken = None;
         for vin in vins.into_iter() {
             console_debug!("trying {} in 'vinlookup'", vin);
-            let pdf = bucket.get(&vin).execute().await;
-            match pdf {
-                Ok(None) => {
+            match archive.get(&vin).await? {
+                None => {
                     console_debug!("checked bucket and found nothing");
-                    match vinlookup::vinlookup(&vin).await {
-                        Ok(data) => {
-                            if data == b"SAP API limits exceeded" {
-                                return Err("limits exceeded downstream".into());
-                            }
-                            let stored = bucket.put(&vin, data.clone()).execute().await;
-                            console_debug!("after stored {}", vin);
-                            match stored {
-                                Ok(_) => {
-                                    let car = Car::from_pdf(data).await;
-                                    match car {
-                                        Ok(Some(mut car)) => {
-                                            let car_id: CarId = car.to_d1(ctx.env.d1("failcat_db")?).await?;
-                                            car.set_id(car_id);
-                                            return Ok(Some(car));
-                                        }
-                                        _ => continue,
-                                    }
-                                }
-                                Err(_) => return Err("couldn't store pdf".into()),
-                            }
-                        }
+                    let data = match vinlookup::vinlookup(&vin).await {
+                        Ok(data) => data,
+                        Err(e) if vinlookup::is_limits_exceeded(&e) => return Err(e),
                         Err(_) => continue,
                     };
-                }
-                Ok(Some(object)) => {
-                    console_debug!("found {} in bucket with size: {:?}", vin, object.size());
-                    if object.size() < 100 {
-                        console_debug!("found broken pdf in bucket for vin:{}", vin);
-                        // VIN is broken
-                        let broken_string = "BROKEN".to_string();
-                        let vin = Vin(vin);
-                        return Ok(Some(Car::new(
-                            vin.clone(),
-                            broken_string.clone(),
-                            broken_string.clone(),
-                            broken_string.clone(),
-                            broken_string.clone(),
-                            broken_string.clone(),
-                            broken_string.clone(),
-                            Utc::now(),
-                            SerialNumber::from(vin),
-                            broken_string,
-                            Some(Utc::now().to_string()),
-                            Some(Utc::now().to_string()),
-                        )));
+                    if (data.len() as u32) < BROKEN_STICKER_SIZE {
+                        console_debug!("upstream sent a broken sticker for vin:{}", vin);
+                        broken = Some((vin, data));
+                        continue;
+                    }
+                    let car = match texts.text_for(&vin, &data).await {
+                        Ok(text) => Car::from_sticker_text(&text),
+                        Err(e) => Err(e),
+                    };
+                    let parse_status = match car {
+                        Ok(Some(_)) => ParseStatus::Parsed,
+                        _ => ParseStatus::Failed,
+                    };
+                    if archive.put(&vin, serial, data, parse_status).await.is_err() {
+                        return Err("couldn't store pdf".into());
                     }
-                    let body = object.body().expect("couldn't get body");
-                    let bytes = body.bytes().await.expect("could not get bytes");
-                    match Car::from_pdf(bytes).await {
+                    console_debug!("after stored {}", vin);
+                    match car {
+                        Ok(Some(car)) => return Ok(Some(car)),
+                        _ => continue,
+                    }
+                }
+                Some(sticker) if sticker.info.is_broken() => {
+                    // Stickers archived before the quarantine existed get moved out of the bucket
+                    console_debug!("found broken pdf in bucket for vin:{}", vin);
+                    archive.delete(&vin).await?;
+                    broken = Some((vin, sticker.bytes));
+                }
+                Some(sticker) => {
+                    console_debug!("found {} in bucket with size: {:?}", vin, sticker.info.size);
+                    let text = texts.text_for(&vin, &sticker.bytes).await?;
+                    match Car::from_sticker_text(&text) {
                         Ok(Some(car)) => {
                             console_debug!("returning car we found {:?}", car);
                             return Ok(Some(car));
@@ -329,9 +460,15 @@ impl Car {
                         }
                     }
                 }
-                Err(e) => return Err(e),
             }
         }
+        if let Some((vin, body)) = broken {
+            console_debug!("only broken stickers for {}, quarantining", serial);
+            BrokenStickerRepository::new(env.d1("failcat_db")?)
+                .quarantine(serial, &vin, &body)
+                .await?;
+            return Ok(Some(Car::placeholder(Vin(vin), serial, CarStatus::Pending)));
+        }
         console_debug!("returning nothing, sadly");
         Ok(None)
     }
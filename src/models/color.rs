@@ -0,0 +1,327 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use super::*;
+
+const INTERIOR_MATERIALS: [&str; 7] = [
+    "NAPPA LEATHER",
+    "LEATHER",
+    "SYNTEX",
+    "SOFTEX",
+    "SUEDE",
+    "VINYL",
+    "CLOTH",
+];
+
+/// Paint codes we've seen on stickers and the name each one goes by. Codes are only split off
+/// sticker text when they're listed here, so add new ones as they show up.
+const PAINT_CODES: [(&str, &str); 6] = [
+    ("ABP", "Aurora Black Pearl"),
+    ("EBB", "Ebony Black"),
+    ("GWP", "Glacial White Pearl"),
+    ("KLG", "Gravity Gray"),
+    ("P2M", "Panthera Metal"),
+    ("SWP", "Snow White Pearl"),
+];
+
+/// Shorthand stickers use for words in color names.
+const ABBREVIATIONS: [(&str, &str); 10] = [
+    ("PRL", "PEARL"),
+    ("MET", "METALLIC"),
+    ("MTLC", "METALLIC"),
+    ("BLK", "BLACK"),
+    ("WHT", "WHITE"),
+    ("GRY", "GRAY"),
+    ("GREY", "GRAY"),
+    ("SLV", "SILVER"),
+    ("SLVR", "SILVER"),
+    ("BLU", "BLUE"),
+];
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ExteriorColor {
+    pub name: String,
+    pub paint_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct InteriorColor {
+    pub name: String,
+    pub material: Option<String>,
+}
+
+/// Splits text into runs of letters and digits and runs of everything else, so words can be
+/// swapped or dropped without losing the separators between them.
+fn runs(s: &str) -> Vec<&str> {
+    let mut runs = vec![];
+    let mut start = 0;
+    let mut previous = None;
+    for (index, c) in s.char_indices() {
+        let word = c.is_ascii_alphanumeric();
+        if previous.map_or(false, |previous| previous != word) {
+            runs.push(&s[start..index]);
+            start = index;
+        }
+        previous = Some(word);
+    }
+    if start < s.len() {
+        runs.push(&s[start..]);
+    }
+    runs
+}
+
+fn title_case(s: &str) -> String {
+    let mut capitalize = true;
+    s.chars()
+        .map(|c| {
+            let c = if capitalize {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            };
+            capitalize = !c.is_ascii_alphanumeric();
+            c
+        })
+        .collect()
+}
+
+/// Uppercases, expands abbreviations, and tidies the spacing and stray separators left around
+/// the name. Slashes inside a name, as in two-tone "BLACK/RED", are kept.
+fn normalize_name(s: &str) -> String {
+    let upper = s.to_uppercase();
+    let expanded: String = runs(&upper)
+        .into_iter()
+        .map(|run| {
+            ABBREVIATIONS
+                .iter()
+                .find(|(short, _)| *short == run)
+                .map_or(run, |(_, long)| long)
+        })
+        .collect();
+    let tidied = expanded
+        .replace("()", " ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let trimmed = tidied.trim_matches(|c: char| c == '/' || c == '-' || c.is_whitespace());
+    title_case(trimmed)
+}
+
+/// Splits a known paint code off the sticker text, which prints it as "NAME (CODE)",
+/// "NAME - CODE", "CODE/NAME" or "NAME/CODE" depending on the sticker.
+fn split_code(raw: &str) -> (String, Option<(&'static str, &'static str)>) {
+    let upper = raw.trim().to_uppercase();
+    let runs = runs(&upper);
+    let code = runs
+        .iter()
+        .find_map(|run| PAINT_CODES.iter().find(|(code, _)| code == run).copied());
+    let name = match code {
+        Some((code, _)) => runs.into_iter().filter(|run| *run != code).collect(),
+        None => upper,
+    };
+    (normalize_name(&name), code)
+}
+
+impl ExteriorColor {
+    /// The catalog name when the code or the name is one we know, the tidied sticker text
+    /// otherwise.
+    pub fn from_sticker(raw: &str) -> Self {
+        let (name, code) = split_code(raw);
+        let code = code.or_else(|| {
+            PAINT_CODES
+                .iter()
+                .find(|(_, known)| known.eq_ignore_ascii_case(&name))
+                .copied()
+        });
+        match code {
+            Some((code, known)) => ExteriorColor {
+                name: known.to_string(),
+                paint_code: Some(code.to_string()),
+            },
+            None => ExteriorColor {
+                name,
+                paint_code: None,
+            },
+        }
+    }
+}
+
+impl InteriorColor {
+    pub fn from_sticker(raw: &str) -> Self {
+        // Trim codes printed in parentheses aren't part of the color
+        let upper = raw.to_uppercase();
+        let upper = match (upper.find('('), upper.rfind(')')) {
+            (Some(open), Some(close)) if open < close => {
+                format!("{} {}", &upper[..open], &upper[close + 1..])
+            }
+            _ => upper,
+        };
+        match INTERIOR_MATERIALS
+            .iter()
+            .find_map(|material| upper.find(material).map(|index| (index, *material)))
+        {
+            Some((index, material)) => {
+                let color = format!("{} {}", &upper[..index], &upper[index + material.len()..]);
+                InteriorColor {
+                    name: normalize_name(&color),
+                    material: Some(title_case(material)),
+                }
+            }
+            None => InteriorColor {
+                name: normalize_name(&upper),
+                material: None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ColorCount {
+    pub model_year: String,
+    pub name: String,
+    /// Paint code, exterior colors only.
+    pub code: Option<String>,
+    /// Seat material, interior colors only.
+    pub material: Option<String>,
+    pub count: i32,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ColorCatalog {
+    pub exterior: Vec<ColorCount>,
+    pub interior: Vec<ColorCount>,
+}
+
+pub struct ColorRepository {
+    d1: Database,
+}
+
+impl ColorRepository {
+    pub fn new(d1: Database) -> Self {
+        ColorRepository { d1 }
+    }
+
+    pub async fn get_all(&self) -> worker::Result<ColorCatalog> {
+        // Rows that predate normalization fall back to their raw sticker text until reparsed
+        let exterior = self
+            .d1
+            .prepare(
+                "SELECT model_year, COALESCE(ext_color_name, ext_color) AS name, ext_paint_code AS code, NULL AS material, COUNT(*) AS count
+                FROM cars WHERE status = 'ok'
+                GROUP BY model_year, name, code
+                ORDER BY model_year DESC, count DESC",
+            )
+            .all()
+            .await?
+            .results::<ColorCount>()?;
+        let interior = self
+            .d1
+            .prepare(
+                "SELECT model_year, COALESCE(int_color_name, int_color) AS name, NULL AS code, int_material AS material, COUNT(*) AS count
+                FROM cars WHERE status = 'ok'
+                GROUP BY model_year, name, material
+                ORDER BY model_year DESC, count DESC",
+            )
+            .all()
+            .await?
+            .results::<ColorCount>()?;
+        Ok(ColorCatalog { exterior, interior })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exterior(name: &str, code: &str) -> ExteriorColor {
+        ExteriorColor {
+            name: name.to_string(),
+            paint_code: Some(code.to_string()),
+        }
+    }
+
+    fn interior(name: &str, material: Option<&str>) -> InteriorColor {
+        InteriorColor {
+            name: name.to_string(),
+            material: material.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn exterior_variants_collapse_to_the_catalog_name_and_code() {
+        for raw in [
+            "SNOW WHITE PEARL (SWP)",
+            "Snow White Prl",
+            "swp/snow white prl",
+            "SNOW  WHITE PEARL - SWP",
+            "SWP",
+        ] {
+            assert_eq!(
+                ExteriorColor::from_sticker(raw),
+                exterior("Snow White Pearl", "SWP"),
+                "{raw}"
+            );
+        }
+        for raw in ["Gravity Grey", "GRAVITY GRY (KLG)", " gravity gray "] {
+            assert_eq!(
+                ExteriorColor::from_sticker(raw),
+                exterior("Gravity Gray", "KLG"),
+                "{raw}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_exterior_colors_are_tidied_without_a_code() {
+        let color = ExteriorColor::from_sticker("DARK  MOSS ");
+        assert_eq!(color.name, "Dark Moss");
+        assert_eq!(color.paint_code, None);
+        // Codes we don't know stay part of the name rather than being guessed at
+        assert_eq!(
+            ExteriorColor::from_sticker("wolf gray m9y").paint_code,
+            None
+        );
+        assert_eq!(ExteriorColor::from_sticker("BLACK/RED").name, "Black/Red");
+    }
+
+    #[test]
+    fn interior_material_is_split_from_the_color() {
+        assert_eq!(
+            InteriorColor::from_sticker("BLACK NAPPA LEATHER (WK)"),
+            interior("Black", Some("Nappa Leather"))
+        );
+        assert_eq!(
+            InteriorColor::from_sticker("Black Nappa Leather"),
+            interior("Black", Some("Nappa Leather"))
+        );
+        assert_eq!(
+            InteriorColor::from_sticker("GRY/BLK SYNTEX"),
+            interior("Gray/Black", Some("Syntex"))
+        );
+        assert_eq!(
+            InteriorColor::from_sticker("SAGE GREEN CLOTH"),
+            interior("Sage Green", Some("Cloth"))
+        );
+        assert_eq!(InteriorColor::from_sticker("tan"), interior("Tan", None));
+    }
+
+    #[test]
+    fn normalizing_a_car_keeps_the_raw_sticker_strings() {
+        let mut car = Car::placeholder(
+            Vin("5XYP5DGC0NG000001".to_string()),
+            SerialNumber(1),
+            CarStatus::Ok,
+        );
+        car.ext_color = "swp/snow white prl".to_string();
+        car.int_color = "BLK SOFTEX".to_string();
+        car.normalize_colors();
+
+        assert_eq!(car.ext_color, "swp/snow white prl");
+        assert_eq!(car.int_color, "BLK SOFTEX");
+        assert_eq!(car.ext_color_name.as_deref(), Some("Snow White Pearl"));
+        assert_eq!(car.ext_paint_code.as_deref(), Some("SWP"));
+        assert_eq!(car.int_color_name.as_deref(), Some("Black"));
+        assert_eq!(car.int_material.as_deref(), Some("Softex"));
+    }
+}
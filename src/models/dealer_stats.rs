@@ -0,0 +1,150 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use super::*;
+
+// Dealers are grouped into a region by the first three digits of their zip
+const REGION_ZIP_PREFIX_LEN: usize = 3;
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct CountByKey {
+    pub key: Option<String>,
+    pub count: i32,
+}
+
+/// How the groups in a `count_by` come back.
+#[derive(Debug, Clone, Copy)]
+enum CountOrder {
+    /// Oldest first, for the monthly timeline.
+    ByKey,
+    /// Most common first.
+    ByCount,
+}
+
+impl CountOrder {
+    fn sql(self) -> &'static str {
+        match self {
+            CountOrder::ByKey => "key ASC",
+            CountOrder::ByCount => "count DESC",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AllocationSummary {
+    allocations: i32,
+    first_serial: Option<i32>,
+    last_serial: Option<i32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct RegionRank {
+    pub region: String,
+    pub rank: i32,
+    pub dealers_in_region: i32,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DealerStats {
+    pub dealer_code: String,
+    pub allocations: i32,
+    pub first_serial: Option<SerialNumber>,
+    pub last_serial: Option<SerialNumber>,
+    pub average_serial_gap: Option<f64>,
+    pub allocations_by_month: Vec<CountByKey>,
+    pub ext_colors: Vec<CountByKey>,
+    pub int_colors: Vec<CountByKey>,
+    pub trims: Vec<CountByKey>,
+    pub region_rank: Option<RegionRank>,
+}
+
+impl DealerRepository {
+    pub async fn stats(&self, dealer_code: &str) -> worker::Result<Option<DealerStats>> {
+        let summary = self
+            .d1
+            .prepare(
+                "SELECT COUNT(*) AS allocations, MIN(serial_number) AS first_serial, MAX(serial_number) AS last_serial FROM cars WHERE sold_to = ? AND status = 'ok'",
+            )
+            .bind(&[dealer_code.into()])?
+            .first::<AllocationSummary>(None)
+            .await?;
+        let summary = match summary {
+            Some(summary) if summary.allocations > 0 => summary,
+            _ => return Ok(None),
+        };
+
+        // The gaps between consecutive serials telescope, so their mean is just the spread
+        let average_serial_gap = match (summary.first_serial, summary.last_serial) {
+            (Some(first), Some(last)) if summary.allocations > 1 => {
+                Some((last - first) as f64 / (summary.allocations - 1) as f64)
+            }
+            _ => None,
+        };
+
+        Ok(Some(DealerStats {
+            dealer_code: dealer_code.to_string(),
+            allocations: summary.allocations,
+            first_serial: summary.first_serial.map(SerialNumber),
+            last_serial: summary.last_serial.map(SerialNumber),
+            average_serial_gap,
+            allocations_by_month: self
+                .count_by(
+                    "strftime('%Y-%m', created_date)",
+                    CountOrder::ByKey,
+                    dealer_code,
+                )
+                .await?,
+            ext_colors: self
+                .count_by("ext_color", CountOrder::ByCount, dealer_code)
+                .await?,
+            int_colors: self
+                .count_by("int_color", CountOrder::ByCount, dealer_code)
+                .await?,
+            trims: self
+                .count_by("car_model", CountOrder::ByCount, dealer_code)
+                .await?,
+            region_rank: self.region_rank(dealer_code).await?,
+        }))
+    }
+
+    // `column` is always one of our own expressions above, never user input
+    async fn count_by(
+        &self,
+        column: &str,
+        order: CountOrder,
+        dealer_code: &str,
+    ) -> worker::Result<Vec<CountByKey>> {
+        let order = order.sql();
+        let sql = format!(
+            "SELECT {column} AS key, COUNT(*) AS count FROM cars WHERE sold_to = ? AND status = 'ok' GROUP BY key ORDER BY {order}"
+        );
+        let statement = self.d1.prepare(&sql);
+        let query = statement.bind(&[dealer_code.into()])?;
+        query.all().await?.results::<CountByKey>()
+    }
+
+    async fn region_rank(&self, dealer_code: &str) -> worker::Result<Option<RegionRank>> {
+        let region = match self.get(dealer_code).await? {
+            Some(dealer) if dealer.zip.len() >= REGION_ZIP_PREFIX_LEN => {
+                dealer.zip[..REGION_ZIP_PREFIX_LEN].to_string()
+            }
+            _ => return Ok(None),
+        };
+
+        let statement = self.d1.prepare(
+            "SELECT d.dealer_code AS key, COUNT(c.id) AS count FROM dealers AS d LEFT JOIN cars AS c ON c.sold_to = d.dealer_code AND c.status = 'ok' WHERE substr(d.zip, 1, ?) = ? GROUP BY d.dealer_code ORDER BY count DESC",
+        );
+        let query = statement.bind(&[(REGION_ZIP_PREFIX_LEN as i32).into(), region.clone().into()])?;
+        let ranking = query.all().await?.results::<CountByKey>()?;
+
+        Ok(ranking
+            .iter()
+            .position(|row| row.key.as_deref() == Some(dealer_code))
+            .map(|position| RegionRank {
+                region,
+                rank: position as i32 + 1,
+                dealers_in_region: ranking.len() as i32,
+            }))
+    }
+}
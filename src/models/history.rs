@@ -0,0 +1,83 @@
+use chrono::Utc;
+use derive_more::Display;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use super::*;
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Display)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeSource {
+    #[display(fmt = "scrape")]
+    Scrape,
+    #[display(fmt = "rescrape")]
+    Rescrape,
+    #[display(fmt = "reparse")]
+    Reparse,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct CarHistoryEntry {
+    pub id: i32,
+    pub car_id: CarId,
+    pub serial_number: SerialNumber,
+    pub field: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub source: ChangeSource,
+    pub changed_at: String,
+}
+
+pub struct CarHistoryRepository {
+    d1: Database,
+}
+
+impl CarHistoryRepository {
+    pub fn new(d1: Database) -> Self {
+        CarHistoryRepository { d1 }
+    }
+
+    /// One insert per change, left to the caller to batch with the update that made them.
+    pub fn record_statements(
+        &self,
+        car_id: CarId,
+        serial_number: SerialNumber,
+        changes: &[FieldChange],
+        source: ChangeSource,
+    ) -> worker::Result<Vec<D1PreparedStatement>> {
+        let changed_at = Utc::now().to_rfc3339();
+        let mut statements = vec![];
+        for change in changes {
+            let statement = self.d1.prepare(
+                "INSERT INTO car_history (car_id, serial_number, field, old_value, new_value, source, changed_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            );
+            statements.push(statement.bind(&[
+                car_id.0.into(),
+                serial_number.0.into(),
+                change.field.into(),
+                change.old_value.clone().into(),
+                change.new_value.clone().into(),
+                source.to_string().into(),
+                changed_at.clone().into(),
+            ])?);
+        }
+        Ok(statements)
+    }
+
+    /// Every recorded change for a car, oldest first.
+    pub async fn get_for_car(&self, car_id: CarId) -> worker::Result<Vec<CarHistoryEntry>> {
+        let statement = self
+            .d1
+            .prepare("SELECT * FROM car_history WHERE car_id = ? ORDER BY changed_at, id");
+        let query = statement.bind(&[car_id.0.into()])?;
+        query.all().await?.results::<CarHistoryEntry>()
+    }
+}
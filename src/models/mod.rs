@@ -5,6 +5,8 @@ use std::{
     num::ParseIntError,
 };
 
+use async_graphql::SimpleObject;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use worker::*;
 
@@ -12,22 +14,65 @@ pub mod car;
 pub use car::*;
 pub mod serial;
 pub use serial::*;
+pub mod dealer_stats;
+pub use dealer_stats::*;
+pub mod broken;
+pub use broken::*;
+pub mod pricing;
+pub use pricing::*;
+pub mod color;
+pub use color::*;
+pub mod history;
+pub use history::*;
+pub mod store;
+pub use store::*;
+pub mod search;
+pub use search::*;
+
+/// `?, ?, ?` for binding a list into an `IN (...)`.
+fn placeholders(count: usize) -> String {
+    vec!["?"; count].join(", ")
+}
 
 #[derive(
-    Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Clone, Display, From, Deref,
+    Debug,
+    Deserialize,
+    Serialize,
+    JsonSchema,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Clone,
+    Display,
+    From,
+    Deref,
 )]
 pub struct Vin(pub String);
 
 
 #[derive(
-    Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display, From, Deref,
+    Debug,
+    Deserialize,
+    Serialize,
+    JsonSchema,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Display,
+    From,
+    Deref,
 )]
 pub struct CarId(pub i32);
 
 
 
 #[serde(rename_all = "lowercase")]
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default)]
 pub enum CarOrder {
     Id,
     #[default]
@@ -46,14 +91,33 @@ pub enum CarQueryError {
 
 
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, SimpleObject)]
 pub struct ScraperLog {
-    id: Option<i32>,
-    found_cars: i32,
-    run_start: String,
-    run_end: String,
-    run_type: String,
-    success: bool,
+    pub id: Option<i32>,
+    pub found_cars: i32,
+    pub run_start: String,
+    pub run_end: String,
+    pub run_type: String,
+    pub success: bool,
+}
+
+pub struct ScraperLogRepository {
+    d1: Database,
+}
+
+impl ScraperLogRepository {
+    pub fn new(d1: Database) -> Self {
+        ScraperLogRepository { d1 }
+    }
+
+    /// Most recent runs first.
+    pub async fn get_recent(&self, limit: i32, offset: i32) -> worker::Result<Vec<ScraperLog>> {
+        let statement = self
+            .d1
+            .prepare("SELECT * FROM scraper_logs ORDER BY id DESC LIMIT ? OFFSET ?");
+        let query = statement.bind(&[limit.into(), offset.into()])?;
+        query.all().await?.results::<ScraperLog>()
+    }
 }
 
 impl ScraperLog {
@@ -96,10 +160,10 @@ impl ScraperLog {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, SimpleObject, Clone)]
 pub struct CarModel {
-    model_code: String,
-    description: String,
+    pub model_code: String,
+    pub description: String,
     // car relationship is omitted here, but can be implemented if needed
 }
 
@@ -121,15 +185,89 @@ impl CarModel {
         let result = query.first::<CarModel>(None).await?;
         Ok(result)
     }
+
+    pub async fn to_d1(&self, d1: &Database) -> worker::Result<()> {
+        let statement = d1.prepare(
+            "INSERT INTO car_models (model_code, description) VALUES (?, ?)
+            ON CONFLICT (model_code) DO UPDATE SET description = excluded.description",
+        );
+        let query = statement.bind(&[
+            self.model_code.clone().into(),
+            self.description.clone().into(),
+        ])?;
+        query.run().await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct CarModelSummary {
+    model_code: String,
+    description: String,
+    car_count: i32,
+    first_serial: Option<SerialNumber>,
+    last_serial: Option<SerialNumber>,
+    typical_msrp: Option<i32>,
+}
+
+pub struct CarModelRepository {
+    d1: Database,
+}
+
+impl CarModelRepository {
+    pub fn new(d1: Database) -> Self {
+        CarModelRepository { d1 }
+    }
+
+    pub async fn get(&self, model_code: &str) -> worker::Result<Option<CarModel>> {
+        let statement = self
+            .d1
+            .prepare("SELECT * FROM car_models WHERE model_code = ?");
+        let query = statement.bind(&[model_code.into()])?;
+        query.first::<CarModel>(None).await
+    }
+
+    /// Models by code, in no particular order. Codes without a model are left out.
+    pub async fn get_many(&self, model_codes: &[String]) -> worker::Result<Vec<CarModel>> {
+        if model_codes.is_empty() {
+            return Ok(vec![]);
+        }
+        let sql = format!(
+            "SELECT * FROM car_models WHERE model_code IN ({})",
+            placeholders(model_codes.len())
+        );
+        let mut bindings = vec![];
+        for model_code in model_codes {
+            bindings.push(model_code.as_str().into());
+        }
+        let query = self.d1.prepare(&sql).bind(&bindings)?;
+        query.all().await?.results::<CarModel>()
+    }
+
+    pub async fn get_all(&self) -> worker::Result<Vec<CarModelSummary>> {
+        // "Typical" is the mean sticker total, which is close enough to the median for a trim
+        let statement = self.d1.prepare(
+            "SELECT m.model_code, m.description, COUNT(c.id) AS car_count, MIN(c.serial_number) AS first_serial, MAX(c.serial_number) AS last_serial, CAST(AVG(p.total_msrp) AS INTEGER) AS typical_msrp
+            FROM car_models AS m
+            LEFT JOIN cars AS c ON c.model_code = m.model_code AND c.status = 'ok'
+            LEFT JOIN car_pricing AS p ON p.car_id = c.id
+            GROUP BY m.model_code
+            ORDER BY car_count DESC",
+        );
+        let d1_result = statement.all().await?;
+        let result = d1_result.results::<CarModelSummary>()?;
+        Ok(result)
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema, SimpleObject, Clone)]
+#[graphql(complex)]
 pub struct Dealer {
-    id: Option<i32>,
-    dealer_code: String,
-    address: String,
-    zip: String,
-    car_count: i32, // Aggregated value, can be calculated when needed
+    pub id: Option<i32>,
+    pub dealer_code: String,
+    pub address: String,
+    pub zip: String,
+    pub car_count: i32, // Aggregated value, can be calculated when needed
                     // cars relationship is omitted here, but can be implemented if needed
 }
 
@@ -151,6 +289,23 @@ impl DealerRepository {
         Ok(result)
     }
 
+    /// Dealers by code, in no particular order. Codes without a dealer are left out.
+    pub async fn get_many(&self, dealer_codes: &[String]) -> worker::Result<Vec<Dealer>> {
+        if dealer_codes.is_empty() {
+            return Ok(vec![]);
+        }
+        let sql = format!(
+            "SELECT * FROM dealers WHERE dealer_code IN ({})",
+            placeholders(dealer_codes.len())
+        );
+        let mut bindings = vec![];
+        for dealer_code in dealer_codes {
+            bindings.push(dealer_code.as_str().into());
+        }
+        let query = self.d1.prepare(&sql).bind(&bindings)?;
+        query.all().await?.results::<Dealer>()
+    }
+
     pub async fn create(&self, dealer: &Dealer) -> worker::Result<i32> {
         let statement = self.d1.prepare(
             "INSERT INTO dealers (dealer_code, address, zip, car_count) VALUES (?, ?, ?, ?)",
@@ -173,6 +328,14 @@ impl DealerRepository {
         let result = d1_result.results::<Dealer>()?;
         Ok(result)
     }
+
+    pub async fn get_paginated(&self, limit: i32, offset: i32) -> worker::Result<Vec<Dealer>> {
+        let statement = self
+            .d1
+            .prepare("SELECT * FROM dealers ORDER BY dealer_code LIMIT ? OFFSET ?");
+        let query = statement.bind(&[limit.into(), offset.into()])?;
+        query.all().await?.results::<Dealer>()
+    }
 }
 
 impl Dealer {
@@ -213,6 +376,9 @@ impl Dealer {
     }
 }
 
+const DEFAULT_FEED_SIZE: i32 = 50;
+const MAX_FEED_SIZE: i32 = 200;
+
 pub struct CarRepository {
     pub d1: Database,
 }
@@ -239,42 +405,53 @@ impl CarRepository {
         let mut sql = "SELECT * FROM cars".to_string();
         let mut bindings = vec![];
 
-        if query.dealer.is_some()
-            || query.minimum_serial.is_some()
-            || query.maximum_serial.is_some()
-            || query.minimum_id.is_some()
-            || query.maximum_maximum.is_some()
-        {
-            sql += " WHERE ";
-
-            if let Some(dealer) = &query.dealer {
-                sql += "sold_to = ? AND ";
-                bindings.push(dealer.into());
-            }
-
-            if let Some(minimum_serial) = &query.minimum_serial {
-                sql += "serial_number >= ? AND ";
-                bindings.push(minimum_serial.0.into());
-            }
-
-            if let Some(maximum_serial) = &query.maximum_serial {
-                sql += "serial_number <= ? AND ";
-                bindings.push(maximum_serial.0.into());
-            }
-
-            if let Some(minimum_id) = &query.minimum_id {
-                sql += "id >= ? AND ";
-                bindings.push(minimum_id.0.into());
-            }
-
-            if let Some(maximum_id) = &query.maximum_maximum {
-                sql += "id <= ? AND ";
-                bindings.push(maximum_id.0.into());
-            }
-
-            // Remove the trailing " AND "
-            sql = sql[0..sql.len() - 5].to_string();
+        // Placeholder rows for broken stickers are hidden unless asked for explicitly
+        sql += " WHERE status = ? AND ";
+        bindings.push(query.status.unwrap_or_default().to_string().into());
+
+        if let Some(dealer) = &query.dealer {
+            sql += "sold_to = ? AND ";
+            bindings.push(dealer.into());
+        }
+
+        if let Some(minimum_serial) = &query.minimum_serial {
+            sql += "serial_number >= ? AND ";
+            bindings.push(minimum_serial.0.into());
+        }
+
+        if let Some(maximum_serial) = &query.maximum_serial {
+            sql += "serial_number <= ? AND ";
+            bindings.push(maximum_serial.0.into());
+        }
+
+        if let Some(minimum_id) = &query.minimum_id {
+            sql += "id >= ? AND ";
+            bindings.push(minimum_id.0.into());
+        }
+
+        if let Some(maximum_id) = &query.maximum_maximum {
+            sql += "id <= ? AND ";
+            bindings.push(maximum_id.0.into());
         }
+
+        if let Some(has_option) = &query.has_option {
+            sql += "id IN (SELECT car_id FROM car_options WHERE package_code = ?) AND ";
+            bindings.push(has_option.to_uppercase().into());
+        }
+
+        if let Some(msrp_min) = &query.msrp_min {
+            sql += "id IN (SELECT car_id FROM car_pricing WHERE total_msrp >= ?) AND ";
+            bindings.push((*msrp_min).into());
+        }
+
+        if let Some(msrp_max) = &query.msrp_max {
+            sql += "id IN (SELECT car_id FROM car_pricing WHERE total_msrp <= ?) AND ";
+            bindings.push((*msrp_max).into());
+        }
+
+        // Remove the trailing " AND "
+        sql = sql[0..sql.len() - 5].to_string();
+
         // Strange bug(?) where the order by clause is not working with the prepared statement
         sql += match query.order {
             Some(CarOrder::Id) => " ORDER BY id DESC LIMIT ? OFFSET ?",
@@ -293,8 +470,39 @@ impl CarRepository {
 
         Ok(d1_result)
     }
+
+    /// Most recently discovered cars, newest first, for the feeds.
+    pub async fn get_recent(&self, query: &FeedQuery) -> worker::Result<Vec<Car>> {
+        let mut sql = "SELECT * FROM cars WHERE status = 'ok'".to_string();
+        let mut bindings = vec![];
+
+        if let Some(dealer) = &query.dealer {
+            sql += " AND sold_to = ?";
+            bindings.push(dealer.into());
+        }
+
+        if let Some(color) = &query.color {
+            sql += " AND (ext_color_name = ? COLLATE NOCASE OR ext_paint_code = ? COLLATE NOCASE OR ext_color = ? COLLATE NOCASE)";
+            bindings.push(color.into());
+            bindings.push(color.into());
+            bindings.push(color.into());
+        }
+
+        sql += " ORDER BY created_date DESC LIMIT ?";
+        bindings.push(
+            query
+                .limit
+                .unwrap_or(DEFAULT_FEED_SIZE)
+                .clamp(1, MAX_FEED_SIZE)
+                .into(),
+        );
+
+        let statement = self.d1.prepare(&sql);
+        let query = statement.bind(&bindings)?;
+        query.all().await?.results()
+    }
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct CarQuery {
     pub dealer: Option<String>,
     pub perpage: Option<i32>,
@@ -304,6 +512,18 @@ pub struct CarQuery {
     pub maximum_serial: Option<SerialNumber>,
     pub minimum_id: Option<SerialNumber>,
     pub maximum_maximum: Option<SerialNumber>,
+    pub status: Option<CarStatus>,
+    pub has_option: Option<String>,
+    pub msrp_min: Option<i32>,
+    pub msrp_max: Option<i32>,
+}
+
+/// Filters for the Atom and RSS feeds.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct FeedQuery {
+    pub dealer: Option<String>,
+    pub color: Option<String>,
+    pub limit: Option<i32>,
 }
 
 
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+
+use async_graphql::SimpleObject;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use super::*;
+use crate::stickers::StickerTextCache;
+
+// How far past a price label we look for its dollar amount
+const PRICE_WINDOW: usize = 120;
+
+const OPTIONS_LABELS: [&str; 2] = ["OPTIONAL EQUIPMENT", "OPTIONS"];
+const BASE_PRICE_LABEL: &str = "BASE PRICE";
+const DESTINATION_LABEL: &str = "DESTINATION";
+const TOTAL_PRICE_LABEL: &str = "TOTAL PRICE";
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, SimpleObject, Clone, PartialEq)]
+pub struct CarOption {
+    pub package_code: String,
+    pub description: String,
+    pub price: Option<i32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, SimpleObject, Clone, Default, PartialEq)]
+pub struct CarPricing {
+    pub base_msrp: Option<i32>,
+    pub destination: Option<i32>,
+    pub total_msrp: Option<i32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct StickerPricing {
+    pub pricing: CarPricing,
+    pub options: Vec<CarOption>,
+}
+
+/// The stored options and pricing of one car, as loaded for many cars at once.
+#[derive(Debug, Default, Clone)]
+pub struct StoredOptions {
+    pub options: Vec<CarOption>,
+    pub pricing: Option<CarPricing>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CarOptionRow {
+    car_id: CarId,
+    package_code: String,
+    description: String,
+    price: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CarPricingRow {
+    car_id: CarId,
+    base_msrp: Option<i32>,
+    destination: Option<i32>,
+    total_msrp: Option<i32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct CarOptions {
+    pub car_id: CarId,
+    pub opt_code: String,
+    pub pricing: Option<CarPricing>,
+    pub options: Vec<CarOption>,
+}
+
+impl StickerPricing {
+    pub fn from_sticker_text(text: &str) -> Self {
+        let text = text.to_uppercase();
+        let pricing = CarPricing {
+            base_msrp: amount_after(&text, BASE_PRICE_LABEL),
+            destination: amount_after(&text, DESTINATION_LABEL),
+            total_msrp: amount_after(&text, TOTAL_PRICE_LABEL),
+        };
+
+        let options_start = OPTIONS_LABELS
+            .iter()
+            .find_map(|label| text.find(label).map(|index| index + label.len()));
+        let options = match options_start {
+            Some(start) => {
+                let section = &text[start..];
+                let end = section.find(DESTINATION_LABEL).unwrap_or(section.len());
+                section[..end]
+                    .lines()
+                    .filter_map(parse_option_line)
+                    .collect()
+            }
+            None => vec![],
+        };
+
+        StickerPricing { pricing, options }
+    }
+}
+
+// Credits are printed as "-$500" or "$-500"
+fn parse_dollars(s: &str) -> Option<i32> {
+    let start = s.find('$')?;
+    let after = s[start + 1..].trim_start();
+    let negative = s[..start].trim_end().ends_with('-') || after.starts_with('-');
+    let digits: String = after
+        .trim_start_matches('-')
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == ',')
+        .filter(|c| c.is_ascii_digit())
+        .collect();
+    let amount = digits.parse::<i32>().ok()?;
+    Some(if negative { -amount } else { amount })
+}
+
+fn amount_after(text: &str, label: &str) -> Option<i32> {
+    let index = text.find(label)? + label.len();
+    let window: String = text[index..].chars().take(PRICE_WINDOW).collect();
+    parse_dollars(&window)
+}
+
+// Option lines look like "CARPET FLOOR MATS (CF) $210"; the code in parens is optional
+fn parse_option_line(line: &str) -> Option<CarOption> {
+    let dollar = line.find('$')?;
+    let description = line[..dollar].trim().trim_end_matches('-').trim_end();
+    if description.is_empty()
+        || description.contains(BASE_PRICE_LABEL)
+        || description.contains(TOTAL_PRICE_LABEL)
+    {
+        return None;
+    }
+
+    let package_code = match (description.rfind('('), description.rfind(')')) {
+        (Some(open), Some(close)) if open < close => description[open + 1..close].trim(),
+        _ => description.split_whitespace().next().unwrap_or_default(),
+    };
+    let description = match description.rfind('(') {
+        Some(open) if open > 0 => description[..open].trim(),
+        _ => description,
+    };
+
+    Some(CarOption {
+        package_code: package_code.to_string(),
+        description: description.to_string(),
+        price: parse_dollars(&line[dollar..]),
+    })
+}
+
+pub struct OptionRepository {
+    d1: Database,
+}
+
+impl OptionRepository {
+    pub fn new(d1: Database) -> Self {
+        OptionRepository { d1 }
+    }
+
+    /// Replaces whatever options and pricing were stored for `car_id` with a fresh parse.
+    /// Runs as one batch, so a failure partway leaves the previous parse in place.
+    pub async fn save(&self, car_id: CarId, sticker: &StickerPricing) -> worker::Result<()> {
+        let mut statements = vec![self
+            .d1
+            .prepare("DELETE FROM car_options WHERE car_id = ?")
+            .bind(&[car_id.0.into()])?];
+
+        for option in &sticker.options {
+            let statement = self.d1.prepare(
+                "INSERT INTO car_options (car_id, package_code, description, price) VALUES (?, ?, ?, ?)",
+            );
+            statements.push(statement.bind(&[
+                car_id.0.into(),
+                option.package_code.clone().into(),
+                option.description.clone().into(),
+                option.price.into(),
+            ])?);
+        }
+
+        let statement = self.d1.prepare(
+            "INSERT INTO car_pricing (car_id, base_msrp, destination, total_msrp) VALUES (?, ?, ?, ?)
+            ON CONFLICT (car_id) DO UPDATE SET base_msrp = excluded.base_msrp, destination = excluded.destination, total_msrp = excluded.total_msrp",
+        );
+        statements.push(statement.bind(&[
+            car_id.0.into(),
+            sticker.pricing.base_msrp.into(),
+            sticker.pricing.destination.into(),
+            sticker.pricing.total_msrp.into(),
+        ])?);
+        self.d1.batch(statements).await?;
+        Ok(())
+    }
+
+    pub async fn get_for_car(&self, car: &Car) -> worker::Result<Option<CarOptions>> {
+        let car_id = match car.id {
+            Some(car_id) => car_id,
+            None => return Ok(None),
+        };
+
+        let statement = self
+            .d1
+            .prepare("SELECT package_code, description, price FROM car_options WHERE car_id = ? ORDER BY id");
+        let options = statement
+            .bind(&[car_id.0.into()])?
+            .all()
+            .await?
+            .results::<CarOption>()?;
+
+        let statement = self.d1.prepare(
+            "SELECT base_msrp, destination, total_msrp FROM car_pricing WHERE car_id = ?",
+        );
+        let pricing = statement
+            .bind(&[car_id.0.into()])?
+            .first::<CarPricing>(None)
+            .await?;
+
+        Ok(Some(CarOptions {
+            car_id,
+            opt_code: car.opt_code.clone(),
+            pricing,
+            options,
+        }))
+    }
+
+    /// Options and pricing for every car in `car_ids`, in one round trip. Cars without any
+    /// still get an empty entry.
+    pub async fn get_for_cars(
+        &self,
+        car_ids: &[CarId],
+    ) -> worker::Result<HashMap<CarId, StoredOptions>> {
+        let mut stored: HashMap<CarId, StoredOptions> = car_ids
+            .iter()
+            .map(|car_id| (*car_id, StoredOptions::default()))
+            .collect();
+        if car_ids.is_empty() {
+            return Ok(stored);
+        }
+
+        let mut bindings = vec![];
+        for car_id in car_ids {
+            bindings.push(car_id.0.into());
+        }
+        let options = self
+            .d1
+            .prepare(&format!(
+                "SELECT car_id, package_code, description, price FROM car_options WHERE car_id IN ({}) ORDER BY id",
+                placeholders(car_ids.len())
+            ))
+            .bind(&bindings)?;
+        let pricing = self
+            .d1
+            .prepare(&format!(
+                "SELECT car_id, base_msrp, destination, total_msrp FROM car_pricing WHERE car_id IN ({})",
+                placeholders(car_ids.len())
+            ))
+            .bind(&bindings)?;
+        let mut results = self.d1.batch(vec![options, pricing]).await?.into_iter();
+        let (options, pricing) = match (results.next(), results.next()) {
+            (Some(options), Some(pricing)) => (
+                options.results::<CarOptionRow>()?,
+                pricing.results::<CarPricingRow>()?,
+            ),
+            _ => return Err("Missing options or pricing results".into()),
+        };
+
+        for row in options {
+            let option = CarOption {
+                package_code: row.package_code,
+                description: row.description,
+                price: row.price,
+            };
+            stored.entry(row.car_id).or_default().options.push(option);
+        }
+        for row in pricing {
+            stored.entry(row.car_id).or_default().pricing = Some(CarPricing {
+                base_msrp: row.base_msrp,
+                destination: row.destination,
+                total_msrp: row.total_msrp,
+            });
+        }
+        Ok(stored)
+    }
+}
+
+/// Stores options and pricing for `car_id` from the cached sticker text, if we have it.
+pub async fn store_sticker_details(env: &Env, car_id: CarId, vin: &Vin) -> worker::Result<()> {
+    let texts = StickerTextCache::new(env.d1("failcat_db")?);
+    if let Some(cached) = texts.get(vin).await? {
+        let sticker = StickerPricing::from_sticker_text(&cached.text);
+        OptionRepository::new(env.d1("failcat_db")?)
+            .save(car_id, &sticker)
+            .await?;
+    }
+    Ok(())
+}
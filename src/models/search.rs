@@ -0,0 +1,80 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+// The trigram tokenizer can't match anything shorter
+const MIN_TERM_LENGTH: usize = 3;
+const DEFAULT_LIMIT: i32 = 20;
+const MAX_LIMIT: i32 = 100;
+// bm25 weights per column: kind, ref, identifier, dealer, colors, description
+const RANK: &str = "bm25(search_index, 0.0, 0.0, 10.0, 5.0, 2.0, 1.0)";
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct SearchQuery {
+    /// VIN fragments, serials, dealer codes or names, colors and trims, in any mix.
+    pub q: String,
+    pub limit: Option<i32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchKind {
+    Car,
+    Dealer,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct SearchResult {
+    pub kind: SearchKind,
+    /// The car id, or the dealer code.
+    #[serde(rename = "ref")]
+    pub reference: String,
+    /// VIN and serial for a car, the code for a dealer.
+    pub identifier: String,
+    /// Where the match was, with the matched text in brackets.
+    pub snippet: String,
+    /// Lower is better.
+    pub rank: f64,
+}
+
+/// Turns free text into an FTS5 query: every term quoted so nothing in it is read as syntax,
+/// and all of them required. `None` when no term is long enough to search for.
+pub fn match_expression(q: &str) -> Option<String> {
+    let terms: Vec<String> = q
+        .split_whitespace()
+        .filter(|term| term.chars().count() >= MIN_TERM_LENGTH)
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect();
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+pub struct SearchRepository {
+    d1: Database,
+}
+
+impl SearchRepository {
+    pub fn new(d1: Database) -> Self {
+        SearchRepository { d1 }
+    }
+
+    /// Cars and dealers matching every term, best first. The index is kept up to date by
+    /// triggers on `cars` and `dealers`, see `migrations/0013_search_index.sql`.
+    pub async fn search(
+        &self,
+        expression: &str,
+        limit: Option<i32>,
+    ) -> Result<Vec<SearchResult>> {
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+        let sql = format!(
+            "SELECT kind, CAST(ref AS TEXT) AS ref, identifier, snippet(search_index, -1, '[', ']', '…', 8) AS snippet, {RANK} AS rank
+            FROM search_index WHERE search_index MATCH ? ORDER BY {RANK} LIMIT ?"
+        );
+        let statement = self.d1.prepare(&sql);
+        let query = statement.bind(&[expression.into(), limit.into()])?;
+        query.all().await?.results::<SearchResult>()
+    }
+}
@@ -3,12 +3,13 @@ use std::num::ParseIntError;
 use std::str::FromStr;
 
 use derive_more::{From, Deref, Add};
+use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
-use worker::RouteContext;
+use worker::Env;
 
 use super::Vin;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Add, Deref, From)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, JsonSchema, Add, Deref, From)]
 pub struct SerialNumber(pub i32);
 
 
@@ -53,6 +54,17 @@ impl Display for SerialNumber {
 }
 
 
+impl Vin {
+    /// The serial in the last six characters, `None` when they aren't all digits.
+    pub fn serial_number(&self) -> Option<SerialNumber> {
+        let tail = self.0.get(11..).filter(|tail| !tail.is_empty())?;
+        if !tail.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        tail.parse::<i32>().ok().map(SerialNumber)
+    }
+}
+
 impl From<Vin> for SerialNumber {
     fn from(vin: Vin) -> Self {
         // SerialNumber is last 6 digits (0 padded) of Vin
@@ -60,8 +72,8 @@ impl From<Vin> for SerialNumber {
     }
 }
 
-pub async fn highest_serial(ctx: &RouteContext<()>) -> SerialNumber {
-    let d1 = ctx.env.d1("failcat_db").expect("Couldn't get db");
+pub async fn highest_serial(env: &Env) -> SerialNumber {
+    let d1 = env.d1("failcat_db").expect("Couldn't get db");
     let statement = d1.prepare("SELECT max(serial_number) FROM cars");
     let rows = statement
         .first::<i32>(Some("max(serial_number)"))
@@ -0,0 +1,247 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use worker::kv::KvStore;
+use worker::*;
+
+use crate::httpcache::{purge_car, Generations};
+
+use super::*;
+
+const DEFAULT_RECONCILE_PAGE_SIZE: i32 = 100;
+const MAX_RECONCILE_PAGE_SIZE: i32 = 500;
+
+/// The one place cars get written. D1 is the source of truth; KV only holds a cached copy of
+/// each row keyed by serial, tagged with the row's `version` so a stale copy can be spotted.
+/// A failed cache write never fails a save, `POST /admin/reconcile` repairs it later.
+pub struct CarStore {
+    d1: Database,
+    kv: KvStore,
+    history: CarHistoryRepository,
+    generations: Generations,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct ReconcileQuery {
+    /// Continue the D1 scan after this car id.
+    pub after_id: Option<i32>,
+    /// Continue the KV scan from this list cursor.
+    pub kv_cursor: Option<String>,
+    pub limit: Option<i32>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct ReconcileReport {
+    pub scanned_d1: i32,
+    pub scanned_kv: i32,
+    /// In D1 but not cached.
+    pub missing: Vec<SerialNumber>,
+    /// Cached with an older version than D1.
+    pub stale: Vec<SerialNumber>,
+    /// Cached but couldn't be read back as a car.
+    pub unreadable: Vec<SerialNumber>,
+    /// Cached without a row in D1, so removed from KV.
+    pub orphaned: Vec<SerialNumber>,
+    pub repaired: i32,
+    pub failed: Vec<SerialNumber>,
+    /// Pass back as `after_id` to continue the D1 scan, unset once it's done.
+    pub after_id: Option<i32>,
+    /// Pass back as `kv_cursor` to continue the KV scan, unset once it's done.
+    pub kv_cursor: Option<String>,
+}
+
+impl CarStore {
+    pub fn new(env: &Env) -> Result<Self> {
+        Ok(CarStore {
+            d1: env.d1("failcat_db")?,
+            kv: env.kv("vinscrapes")?,
+            history: CarHistoryRepository::new(env.d1("failcat_db")?),
+            generations: Generations::new(env)?,
+        })
+    }
+
+    async fn cached(&self, serial_number: SerialNumber) -> Result<Option<Car>> {
+        Ok(self.kv.get(&serial_number.to_string()).json().await?)
+    }
+
+    async fn cache(&self, car: &Car) -> Result<()> {
+        self.kv
+            .put(&car.serial_number.to_string(), car)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    async fn cache_or_log(&self, car: &Car) {
+        if let Err(e) = self.cache(car).await {
+            console_error!("couldn't cache {} in kv: {:?}", car.serial_number, e);
+        }
+    }
+
+    /// Reads through the cache, falling back to D1 when the cached copy is missing or unreadable.
+    pub async fn get(&self, serial_number: SerialNumber) -> Result<Option<Car>> {
+        match self.cached(serial_number).await {
+            Ok(Some(car)) => return Ok(Some(car)),
+            Ok(None) => {}
+            Err(e) => console_warn!("kv read for {} failed, using d1: {:?}", serial_number, e),
+        }
+        let car = Car::from_d1_by_serial(serial_number, &self.d1).await?;
+        if let Some(car) = &car {
+            self.cache_or_log(car).await;
+        }
+        Ok(car)
+    }
+
+    /// Inserts a newly found car and caches it. A `Conflict` means the car was already stored,
+    /// most likely by a concurrent scrape, and nothing was written.
+    pub async fn insert(&self, car: &Car) -> Result<InsertOutcome> {
+        let outcome = car.to_d1(&self.d1).await?;
+        if let InsertOutcome::Inserted(_) = outcome {
+            self.recache(car.serial_number, None).await?;
+        }
+        Ok(outcome)
+    }
+
+    /// Inserts the car, or updates the stored row for its serial and records what changed in
+    /// `car_history`. Placeholders being filled in aren't recorded since they had nothing to
+    /// change. Hands back the row as stored, which is also what gets cached.
+    pub async fn save(&self, car: &Car, source: ChangeSource) -> Result<(Car, Vec<FieldChange>)> {
+        let (changes, previous_sold_to) = match car.to_d1(&self.d1).await? {
+            InsertOutcome::Inserted(_) => (vec![], None),
+            InsertOutcome::Conflict(_) => {
+                let (changes, previous) = self.update(car, source).await?;
+                (changes, Some(previous.sold_to))
+            }
+        };
+        let stored = self
+            .recache(car.serial_number, previous_sold_to.as_deref())
+            .await?;
+        Ok((stored, changes))
+    }
+
+    /// Hands back what changed along with the row as it was before the update.
+    async fn update(&self, car: &Car, source: ChangeSource) -> Result<(Vec<FieldChange>, Car)> {
+        let stored = match Car::from_d1_by_serial(car.serial_number, &self.d1).await? {
+            Some(stored) => stored,
+            None => return Err(format!("VIN {} is stored under another serial", car.vin).into()),
+        };
+        let changes = stored.changes(car);
+        if changes.is_empty()
+            && stored.status == car.status
+            && stored.parser_version == car.parser_version
+        {
+            return Ok((changes, stored));
+        }
+
+        let car_id = match stored.id {
+            Some(car_id) => car_id,
+            None => return Err(format!("Stored car {} has no id", car.serial_number).into()),
+        };
+        if let Some(car_model) = car.car_model_entry() {
+            car_model.to_d1(&self.d1).await?;
+        }
+
+        // One batch, so the row never changes without its history being recorded.
+        let mut statements = vec![car.replace_statement(&self.d1)?];
+        let changes = if stored.status == CarStatus::Ok {
+            statements.extend(self.history.record_statements(
+                car_id,
+                car.serial_number,
+                &changes,
+                source,
+            )?);
+            changes
+        } else {
+            vec![]
+        };
+        self.d1.batch(statements).await?;
+        Ok((changes, stored))
+    }
+
+    /// Copies the D1 row for a serial into KV, invalidates the cached responses it shows up in
+    /// and hands it back. `previous_sold_to` is the dealer the car was under before this write.
+    /// Unlike the KV copy nothing repairs a stale cached response, so a failed purge fails the
+    /// write; the row is saved by then and saving it again purges again.
+    async fn recache(
+        &self,
+        serial_number: SerialNumber,
+        previous_sold_to: Option<&str>,
+    ) -> Result<Car> {
+        let stored = match Car::from_d1_by_serial(serial_number, &self.d1).await? {
+            Some(stored) => stored,
+            None => return Err(format!("Couldn't find car {} we saved", serial_number).into()),
+        };
+        self.cache_or_log(&stored).await;
+        purge_car(&self.generations, &stored, previous_sold_to).await?;
+        Ok(stored)
+    }
+
+    pub async fn set_status(&self, serial_number: SerialNumber, status: CarStatus) -> Result<()> {
+        Car::set_status_d1(serial_number, status, &self.d1).await?;
+        self.recache(serial_number, None).await?;
+        Ok(())
+    }
+
+    /// Checks one page of D1 rows against their cached copies and one page of cached keys
+    /// against D1, recaching anything missing or stale and dropping cache entries without a row.
+    pub async fn reconcile(&self, query: ReconcileQuery) -> Result<ReconcileReport> {
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_RECONCILE_PAGE_SIZE)
+            .clamp(1, MAX_RECONCILE_PAGE_SIZE);
+        let mut report = ReconcileReport::default();
+
+        let statement = self
+            .d1
+            .prepare("SELECT * FROM cars WHERE id > ? ORDER BY id LIMIT ?");
+        let cars = statement
+            .bind(&[query.after_id.unwrap_or(0).into(), limit.into()])?
+            .all()
+            .await?
+            .results::<Car>()?;
+        if cars.len() as i32 == limit {
+            report.after_id = cars.last().and_then(|car| car.id).map(|car_id| car_id.0);
+        }
+        for car in cars {
+            report.scanned_d1 += 1;
+            let serial_number = car.serial_number;
+            match self.cached(serial_number).await {
+                Ok(Some(cached)) if cached.id == car.id && cached.version >= car.version => {
+                    continue
+                }
+                Ok(Some(_)) => report.stale.push(serial_number),
+                Ok(None) => report.missing.push(serial_number),
+                Err(_) => report.unreadable.push(serial_number),
+            }
+            match self.cache(&car).await {
+                Ok(()) => report.repaired += 1,
+                Err(e) => {
+                    console_error!("couldn't recache {}: {:?}", serial_number, e);
+                    report.failed.push(serial_number);
+                }
+            }
+        }
+
+        let mut list = self.kv.list().limit(limit as u64);
+        if let Some(cursor) = query.kv_cursor {
+            list = list.cursor(cursor);
+        }
+        let keys = list.execute().await?;
+        if !keys.list_complete {
+            report.kv_cursor = keys.cursor;
+        }
+        for key in keys.keys {
+            report.scanned_kv += 1;
+            // Only serial keys belong to cars, anything else in the namespace is left alone
+            let serial_number = match key.name.parse::<i32>() {
+                Ok(serial) => SerialNumber(serial),
+                Err(_) => continue,
+            };
+            if Car::from_d1_serial(serial_number, &self.d1).await?.is_none() {
+                self.kv.delete(&key.name).await?;
+                report.orphaned.push(serial_number);
+            }
+        }
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,294 @@
+use reqwest_wasm::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::models::Car;
+
+use super::post_json;
+use super::webhooks::SubscriptionFilter;
+
+// Discord caps a message at 10 embeds; past that a run is posted as a plain list instead
+const DISCORD_MAX_EMBEDS: usize = 10;
+// Discord rejects message content and embed titles past these many characters
+const DISCORD_MAX_CONTENT: usize = 2000;
+const DISCORD_MAX_TITLE: usize = 256;
+// Slack caps a message at 50 blocks
+const SLACK_MAX_BLOCKS: usize = 50;
+const EMBED_COLOR: u32 = 0x05141f;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelKind {
+    Discord,
+    Slack,
+}
+
+/// One incoming-webhook url, configured as a JSON list in the `NOTIFICATION_CHANNELS` secret.
+/// Pointing `url` at a local server is enough to see exactly what would be posted.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Channel {
+    pub kind: ChannelKind,
+    pub url: String,
+    #[serde(default)]
+    pub filter: SubscriptionFilter,
+}
+
+pub fn channels(env: &Env) -> Vec<Channel> {
+    let channels: Vec<Channel> = match env.secret("NOTIFICATION_CHANNELS") {
+        Ok(secret) => serde_json::from_str(&secret.to_string()).unwrap_or_else(|e| {
+            console_error!("couldn't parse NOTIFICATION_CHANNELS: {:?}", e);
+            vec![]
+        }),
+        Err(_) => vec![],
+    };
+    channels
+        .into_iter()
+        .filter(|channel| match channel.filter.validate() {
+            Ok(()) => true,
+            Err(e) => {
+                console_error!("skipping {:?} channel: {}", channel.kind, e);
+                false
+            }
+        })
+        .collect()
+}
+
+fn sticker_link(public_url: Option<&str>, car: &Car) -> Option<String> {
+    public_url.map(|base| format!("{}/stickers/{}", base.trim_end_matches('/'), car.vin))
+}
+
+fn trim(car: &Car) -> &str {
+    car.model_code.as_deref().unwrap_or(&car.car_model)
+}
+
+fn exterior(car: &Car) -> &str {
+    car.ext_color_name.as_deref().unwrap_or(&car.ext_color)
+}
+
+fn interior(car: &Car) -> &str {
+    car.int_color_name.as_deref().unwrap_or(&car.int_color)
+}
+
+fn summary_line(car: &Car) -> String {
+    format!(
+        "#{} {} - {} / {} - dealer {}",
+        car.serial_number,
+        trim(car),
+        exterior(car),
+        interior(car),
+        car.sold_to
+    )
+}
+
+/// Cuts `s` down to `max` characters, marking the cut with an ellipsis.
+fn truncate(s: &str, max: usize) -> String {
+    if s.chars().count() <= max {
+        return s.to_string();
+    }
+    let mut truncated: String = s.chars().take(max - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Packs the lines into as few messages as fit under Discord's content limit, each starting
+/// with `header`.
+fn discord_list(header: &str, lines: &[String]) -> Vec<Value> {
+    let budget = DISCORD_MAX_CONTENT - header.chars().count() - 1;
+    let mut messages = vec![];
+    let mut body = String::new();
+    for line in lines {
+        let line = truncate(line, budget);
+        if !body.is_empty() && body.chars().count() + 1 + line.chars().count() > budget {
+            messages.push(json!({ "content": format!("{header}\n{body}") }));
+            body.clear();
+        }
+        if !body.is_empty() {
+            body.push('\n');
+        }
+        body.push_str(&line);
+    }
+    if !body.is_empty() {
+        messages.push(json!({ "content": format!("{header}\n{body}") }));
+    }
+    messages
+}
+
+pub fn discord_messages(cars: &[&Car], public_url: Option<&str>) -> Vec<Value> {
+    if cars.len() > DISCORD_MAX_EMBEDS {
+        let lines: Vec<String> = cars.iter().map(|car| summary_line(car)).collect();
+        return discord_list(&format!("**{} new cars found**", cars.len()), &lines);
+    }
+
+    let embeds: Vec<Value> = cars
+        .iter()
+        .map(|car| {
+            json!({
+                "title": truncate(
+                    &format!("Serial {} - {}", car.serial_number, car.car_model),
+                    DISCORD_MAX_TITLE,
+                ),
+                "url": sticker_link(public_url, car),
+                "color": EMBED_COLOR,
+                "timestamp": car.created_date.to_rfc3339(),
+                "fields": [
+                    { "name": "Trim", "value": trim(car), "inline": true },
+                    { "name": "Exterior", "value": exterior(car), "inline": true },
+                    { "name": "Interior", "value": interior(car), "inline": true },
+                    { "name": "Dealer", "value": car.sold_to, "inline": true },
+                    { "name": "VIN", "value": car.vin.0, "inline": false },
+                ],
+            })
+        })
+        .collect();
+    vec![json!({
+        "content": format!("{} new car{} found", cars.len(), if cars.len() == 1 { "" } else { "s" }),
+        "embeds": embeds,
+    })]
+}
+
+pub fn slack_messages(cars: &[&Car], public_url: Option<&str>) -> Vec<Value> {
+    let blocks: Vec<Value> = cars
+        .iter()
+        .map(|car| {
+            let title = match sticker_link(public_url, car) {
+                Some(link) => format!("*<{}|Serial {}>*", link, car.serial_number),
+                None => format!("*Serial {}*", car.serial_number),
+            };
+            json!({
+                "type": "section",
+                "text": {
+                    "type": "mrkdwn",
+                    "text": format!(
+                        "{} - {}\nExterior: {} | Interior: {}\nDealer: {} | VIN: {}",
+                        title,
+                        trim(car),
+                        exterior(car),
+                        interior(car),
+                        car.sold_to,
+                        car.vin
+                    ),
+                },
+            })
+        })
+        .collect();
+    blocks
+        .chunks(SLACK_MAX_BLOCKS)
+        .map(|chunk| {
+            json!({
+                "text": format!("{} new car{} found", cars.len(), if cars.len() == 1 { "" } else { "s" }),
+                "blocks": chunk,
+            })
+        })
+        .collect()
+}
+
+/// Posts every car from one run to each configured channel whose filter it matches, batched
+/// into as few messages as the chat service allows.
+pub async fn notify_channels(env: &Env, cars: &[Car]) -> Result<()> {
+    let channels = channels(env);
+    if channels.is_empty() || cars.is_empty() {
+        return Ok(());
+    }
+    let public_url = env.var("PUBLIC_URL").ok().map(|url| url.to_string());
+
+    for channel in channels {
+        let matching: Vec<&Car> = cars
+            .iter()
+            .filter(|car| channel.filter.matches(car))
+            .collect();
+        if matching.is_empty() {
+            continue;
+        }
+
+        let messages = match channel.kind {
+            ChannelKind::Discord => discord_messages(&matching, public_url.as_deref()),
+            ChannelKind::Slack => slack_messages(&matching, public_url.as_deref()),
+        };
+        for message in messages {
+            match post_json(&channel.url, HeaderMap::new(), message.to_string()).await {
+                Ok(status) if (200..300).contains(&status) => {}
+                Ok(status) => console_error!("{:?} channel answered {}", channel.kind, status),
+                Err(e) => console_error!("{:?} channel failed: {:?}", channel.kind, e),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CarStatus, SerialNumber, Vin};
+
+    fn car(serial: i32, car_model: &str) -> Car {
+        let mut car = Car::placeholder(
+            Vin(format!("5XYP5DGC0NG{serial:06}")),
+            SerialNumber(serial),
+            CarStatus::Ok,
+        );
+        car.car_model = car_model.to_string();
+        car.sold_to = "CA123".to_string();
+        car
+    }
+
+    fn contents(messages: &[Value]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message["content"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn discord_sends_a_few_cars_as_one_message_of_embeds() {
+        let cars: Vec<Car> = (1..=3).map(|serial| car(serial, "TELLURIDE SX")).collect();
+        let cars: Vec<&Car> = cars.iter().collect();
+        let messages = discord_messages(&cars, Some("https://failcat.example.com/"));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["embeds"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            messages[0]["embeds"][0]["url"],
+            "https://failcat.example.com/stickers/5XYP5DGC0NG000001"
+        );
+    }
+
+    #[test]
+    fn discord_lists_stay_under_the_content_limit() {
+        let model = "X".repeat(300);
+        let cars: Vec<Car> = (1..=40).map(|serial| car(serial, &model)).collect();
+        let cars: Vec<&Car> = cars.iter().collect();
+        let messages = discord_messages(&cars, None);
+        assert!(messages.len() > 1);
+        for content in contents(&messages) {
+            assert!(content.chars().count() <= DISCORD_MAX_CONTENT);
+            assert!(content.starts_with("**40 new cars found**"));
+        }
+        let listed: usize = contents(&messages)
+            .iter()
+            .map(|content| content.lines().count() - 1)
+            .sum();
+        assert_eq!(listed, 40);
+    }
+
+    #[test]
+    fn discord_truncates_long_titles() {
+        let long = car(1, &"X".repeat(400));
+        let messages = discord_messages(&[&long], None);
+        let title = messages[0]["embeds"][0]["title"].as_str().unwrap();
+        assert_eq!(title.chars().count(), DISCORD_MAX_TITLE);
+        assert!(title.ends_with('…'));
+    }
+
+    #[test]
+    fn slack_splits_runs_into_block_limited_messages() {
+        let cars: Vec<Car> = (1..=60).map(|serial| car(serial, "TELLURIDE SX")).collect();
+        let cars: Vec<&Car> = cars.iter().collect();
+        let messages = slack_messages(&cars, None);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0]["blocks"].as_array().unwrap().len(),
+            SLACK_MAX_BLOCKS
+        );
+        assert_eq!(messages[1]["blocks"].as_array().unwrap().len(), 10);
+    }
+}
@@ -0,0 +1,75 @@
+use std::cell::Cell;
+
+use reqwest_wasm::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use reqwest_wasm::Client;
+use worker::*;
+
+use crate::models::Car;
+
+pub mod chat;
+pub mod watch;
+pub mod webhooks;
+
+thread_local! {
+    // Set when `cars_created` queues webhook deliveries, so the request or batch that found the
+    // cars sends them once it's done. An isolate runs on one thread, so a request sharing it
+    // may pick the flag up instead, which sends the same deliveries just as well.
+    static DELIVERIES_QUEUED: Cell<bool> = Cell::new(false);
+}
+
+/// Whether webhook deliveries were queued since the last call. Clears the flag.
+pub fn take_queued_deliveries() -> bool {
+    DELIVERIES_QUEUED.with(|queued| queued.replace(false))
+}
+
+/// Posts a JSON body and hands back the response status, leaving retries to the caller.
+pub async fn post_json(url: &str, mut headers: HeaderMap, body: String) -> Result<u16> {
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    let client = Client::builder()
+        .default_headers(headers)
+        .build()
+        .map_err(|e| Error::from(e.to_string()))?;
+    let response = client
+        .post(url)
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| Error::from(e.to_string()))?;
+    Ok(response.status().as_u16())
+}
+
+/// Fans a newly saved car out to everyone listening for it. Failures are only logged so a bad
+/// subscriber can never fail the scrape that found the car.
+pub async fn car_created(env: &Env, car: &Car) {
+    cars_created(env, std::slice::from_ref(car)).await
+}
+
+/// Same as `car_created` for every car found in one run, so chat channels get a single batch.
+/// `scraper::leased::drain` sends its whole run through here.
+pub async fn cars_created(env: &Env, cars: &[Car]) {
+    if cars.is_empty() {
+        return;
+    }
+    for car in cars {
+        match webhooks::queue_car_created(env, car).await {
+            Ok(0) => {}
+            Ok(_) => DELIVERIES_QUEUED.with(|queued| queued.set(true)),
+            Err(e) => {
+                console_error!("queueing webhooks for {} failed: {:?}", car.serial_number, e)
+            }
+        }
+        if let Err(e) = watch::notify_found(env, car).await {
+            console_error!("watch notifications for {} failed: {:?}", car.serial_number, e);
+        }
+    }
+    if let Err(e) = chat::notify_channels(env, cars).await {
+        console_error!("chat notifications failed: {:?}", e);
+    }
+}
+
+/// Tells watchers when a stored car's details change, e.g. after a sticker is reparsed.
+pub async fn car_changed(env: &Env, car: &Car, fields: &[&str]) {
+    if let Err(e) = watch::notify_changed(env, car, fields).await {
+        console_error!("watch notifications for {} failed: {:?}", car.serial_number, e);
+    }
+}
@@ -0,0 +1,266 @@
+use chrono::Utc;
+use reqwest_wasm::header::{HeaderMap, HeaderValue};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::*;
+
+use crate::models::{Car, CarId, CarStatus, SerialNumber, Vin};
+
+use super::post_json;
+use super::webhooks::{generate_secret, post_signed};
+
+pub const WATCH_FOUND: &str = "watch.found";
+pub const WATCH_CHANGED: &str = "watch.changed";
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct NewWatch {
+    pub vin: Option<Vin>,
+    pub serial_number: Option<SerialNumber>,
+    pub webhook_url: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Watch {
+    pub id: i32,
+    pub serial_number: SerialNumber,
+    pub vin: Option<Vin>,
+    pub webhook_url: Option<String>,
+    pub email: Option<String>,
+    /// Only ever returned when the watch is created.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub secret: String,
+    pub found_car_id: Option<CarId>,
+    pub last_checked_at: Option<String>,
+    pub last_notified_at: Option<String>,
+    pub created_at: String,
+}
+
+/// What `GET /watch/:id` shows: whether the car turned up, without where it'd be sent.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct WatchStatus {
+    pub id: i32,
+    pub serial_number: SerialNumber,
+    pub vin: Option<Vin>,
+    pub has_webhook: bool,
+    pub has_email: bool,
+    pub found_car_id: Option<CarId>,
+    pub last_checked_at: Option<String>,
+    pub last_notified_at: Option<String>,
+    pub created_at: String,
+}
+
+impl From<Watch> for WatchStatus {
+    fn from(watch: Watch) -> Self {
+        WatchStatus {
+            id: watch.id,
+            serial_number: watch.serial_number,
+            vin: watch.vin,
+            has_webhook: watch.webhook_url.is_some(),
+            has_email: watch.email.is_some(),
+            found_car_id: watch.found_car_id,
+            last_checked_at: watch.last_checked_at,
+            last_notified_at: watch.last_notified_at,
+            created_at: watch.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WatchPayload<'a> {
+    pub event: &'a str,
+    pub sent_at: String,
+    pub watch_id: i32,
+    pub car: &'a Car,
+    pub changed_fields: &'a [&'a str],
+}
+
+#[derive(Debug, Deserialize)]
+struct WatchedSerial {
+    serial_number: SerialNumber,
+}
+
+pub struct WatchRepository {
+    d1: Database,
+}
+
+impl WatchRepository {
+    pub fn new(d1: Database) -> Self {
+        WatchRepository { d1 }
+    }
+
+    /// Watches on a serial that's already been found start out with the car attached.
+    pub async fn create(&self, serial_number: SerialNumber, new: NewWatch) -> Result<Watch> {
+        let found = Car::from_d1_by_serial(serial_number, &self.d1)
+            .await?
+            .filter(|car| car.status == CarStatus::Ok)
+            .and_then(|car| car.id);
+        let statement = self.d1.prepare(
+            "INSERT INTO watches (serial_number, vin, webhook_url, email, secret, found_car_id, created_at) VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING *",
+        );
+        let query = statement.bind(&[
+            serial_number.0.into(),
+            new.vin.map(|vin| vin.0).into(),
+            new.webhook_url.into(),
+            new.email.into(),
+            generate_secret().into(),
+            found.map(|car_id| car_id.0).into(),
+            Utc::now().to_rfc3339().into(),
+        ])?;
+        match query.first::<Watch>(None).await? {
+            Some(watch) => Ok(watch),
+            None => Err("No watch created".into()),
+        }
+    }
+
+    pub async fn get(&self, id: i32) -> Result<Option<Watch>> {
+        let statement = self.d1.prepare("SELECT * FROM watches WHERE id = ?");
+        let query = statement.bind(&[id.into()])?;
+        query.first::<Watch>(None).await
+    }
+
+    pub async fn for_serial(&self, serial_number: SerialNumber) -> Result<Vec<Watch>> {
+        let statement = self
+            .d1
+            .prepare("SELECT * FROM watches WHERE serial_number = ?");
+        let query = statement.bind(&[serial_number.0.into()])?;
+        query.all().await?.results::<Watch>()
+    }
+
+    /// Watched serials without a car yet, least recently checked first.
+    pub async fn due_serials(&self, limit: i32) -> Result<Vec<SerialNumber>> {
+        let statement = self.d1.prepare(
+            "SELECT serial_number, MAX(COALESCE(last_checked_at, '')) AS checked FROM watches AS w
+            WHERE NOT EXISTS (SELECT 1 FROM cars AS c WHERE c.serial_number = w.serial_number AND c.status = 'ok')
+            GROUP BY serial_number
+            ORDER BY checked
+            LIMIT ?",
+        );
+        let query = statement.bind(&[limit.into()])?;
+        Ok(query
+            .all()
+            .await?
+            .results::<WatchedSerial>()?
+            .into_iter()
+            .map(|watched| watched.serial_number)
+            .collect())
+    }
+
+    pub async fn touch(&self, serial_number: SerialNumber) -> Result<()> {
+        let statement = self
+            .d1
+            .prepare("UPDATE watches SET last_checked_at = ? WHERE serial_number = ?");
+        let query = statement.bind(&[Utc::now().to_rfc3339().into(), serial_number.0.into()])?;
+        query.run().await?;
+        Ok(())
+    }
+
+    async fn mark_notified(&self, id: i32, car_id: Option<CarId>) -> Result<()> {
+        let statement = self.d1.prepare(
+            "UPDATE watches SET found_car_id = COALESCE(found_car_id, ?), last_notified_at = ? WHERE id = ?",
+        );
+        let query = statement.bind(&[
+            car_id.map(|car_id| car_id.0).into(),
+            Utc::now().to_rfc3339().into(),
+            id.into(),
+        ])?;
+        query.run().await?;
+        Ok(())
+    }
+}
+
+fn email_text(event: &str, car: &Car, changed_fields: &[&str]) -> (String, String) {
+    let details = format!(
+        "Serial {} ({})\nVIN: {}\nExterior: {}\nInterior: {}\nDealer: {}",
+        car.serial_number,
+        car.car_model,
+        car.vin,
+        car.ext_color_name.as_deref().unwrap_or(&car.ext_color),
+        car.int_color_name.as_deref().unwrap_or(&car.int_color),
+        car.sold_to
+    );
+    if event == WATCH_FOUND {
+        (
+            format!("Your car #{} has a window sticker", car.serial_number),
+            details,
+        )
+    } else {
+        (
+            format!("Your car #{} was updated", car.serial_number),
+            format!("Changed: {}\n\n{}", changed_fields.join(", "), details),
+        )
+    }
+}
+
+/// Emails go through whatever mail relay `EMAIL_WEBHOOK_URL` points at, posted as
+/// `{"to", "subject", "text"}`.
+async fn send_email(env: &Env, to: &str, subject: String, text: String) -> Result<()> {
+    let url = match env.secret("EMAIL_WEBHOOK_URL") {
+        Ok(url) => url.to_string(),
+        Err(_) => return Err("EMAIL_WEBHOOK_URL isn't configured".into()),
+    };
+    let body = json!({ "to": to, "subject": subject, "text": text });
+    match post_json(&url, HeaderMap::new(), body.to_string()).await? {
+        status if (200..300).contains(&status) => Ok(()),
+        status => Err(format!("email relay answered {status}").into()),
+    }
+}
+
+/// Whether the webhook or the email got through. A watch is only marked notified when one did,
+/// so the next check tries again otherwise.
+async fn notify(env: &Env, watch: &Watch, event: &str, car: &Car, changed_fields: &[&str]) -> bool {
+    let mut delivered = false;
+    if let Some(url) = &watch.webhook_url {
+        let payload = WatchPayload {
+            event,
+            sent_at: Utc::now().to_rfc3339(),
+            watch_id: watch.id,
+            car,
+            changed_fields,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Failcat-Watch", HeaderValue::from(watch.id));
+        let sent = match serde_json::to_string(&payload) {
+            Ok(payload) => post_signed(url, &watch.secret, event, headers, &payload).await,
+            Err(e) => Err(e.into()),
+        };
+        match sent {
+            Ok(status) if (200..300).contains(&status) => delivered = true,
+            Ok(status) => console_error!("watch {} webhook answered {}", watch.id, status),
+            Err(e) => console_error!("watch {} webhook failed: {:?}", watch.id, e),
+        }
+    }
+    if let Some(email) = &watch.email {
+        let (subject, text) = email_text(event, car, changed_fields);
+        match send_email(env, email, subject, text).await {
+            Ok(()) => delivered = true,
+            Err(e) => console_error!("watch {} email failed: {:?}", watch.id, e),
+        }
+    }
+    delivered
+}
+
+/// Lets everyone watching this serial know the car showed up, once per watch.
+pub async fn notify_found(env: &Env, car: &Car) -> Result<()> {
+    let repo = WatchRepository::new(env.d1("failcat_db")?);
+    for watch in repo.for_serial(car.serial_number).await? {
+        if watch.found_car_id.is_some() && watch.last_notified_at.is_some() {
+            continue;
+        }
+        if notify(env, &watch, WATCH_FOUND, car, &[]).await {
+            repo.mark_notified(watch.id, car.id).await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn notify_changed(env: &Env, car: &Car, changed_fields: &[&str]) -> Result<()> {
+    let repo = WatchRepository::new(env.d1("failcat_db")?);
+    for watch in repo.for_serial(car.serial_number).await? {
+        if notify(env, &watch, WATCH_CHANGED, car, changed_fields).await {
+            repo.mark_notified(watch.id, car.id).await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,440 @@
+use chrono::Utc;
+use derive_more::Display;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use reqwest_wasm::header::{HeaderMap, HeaderValue};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use worker::*;
+
+use crate::models::{Car, CarId, SerialNumber};
+
+use super::post_json;
+
+pub const CAR_CREATED: &str = "car.created";
+
+const MAX_ATTEMPTS: i32 = 8;
+// Deliveries sent per run, the rest wait for the next one
+const DELIVERY_BATCH_SIZE: i32 = 50;
+// How long a sender holds a delivery before another run may pick it up again
+const DELIVERY_LEASE_MINUTES: i64 = 5;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Default, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct SubscriptionFilter {
+    pub dealer_codes: Option<Vec<String>>,
+    pub colors: Option<Vec<String>>,
+    pub trims: Option<Vec<String>>,
+    pub minimum_serial: Option<SerialNumber>,
+    pub maximum_serial: Option<SerialNumber>,
+    /// Radius filters aren't supported, there's no zip code data to measure distances with.
+    /// Kept so requests using them are refused rather than silently matching everything.
+    pub zip: Option<String>,
+    pub radius_miles: Option<f64>,
+}
+
+fn matches_any(wanted: &Option<Vec<String>>, values: &[Option<&str>]) -> bool {
+    match wanted {
+        None => true,
+        Some(wanted) => wanted.iter().any(|wanted| {
+            values
+                .iter()
+                .flatten()
+                .any(|value| value.eq_ignore_ascii_case(wanted.trim()))
+        }),
+    }
+}
+
+impl SubscriptionFilter {
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.zip.is_some() || self.radius_miles.is_some() {
+            return Err("zip and radius_miles filters aren't supported".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, car: &Car) -> bool {
+        // Radius filters saved before they were refused never matched anything, and still don't
+        if self.validate().is_err() {
+            return false;
+        }
+        let dealer = [Some(car.sold_to.as_str())];
+        let colors = [
+            Some(car.ext_color.as_str()),
+            car.ext_color_name.as_deref(),
+            car.ext_paint_code.as_deref(),
+        ];
+        let trims = [Some(car.car_model.as_str()), car.model_code.as_deref()];
+        matches_any(&self.dealer_codes, &dealer)
+            && matches_any(&self.colors, &colors)
+            && matches_any(&self.trims, &trims)
+            && self
+                .minimum_serial
+                .map_or(true, |min| car.serial_number >= min)
+            && self
+                .maximum_serial
+                .map_or(true, |max| car.serial_number <= max)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct NewSubscription {
+    pub url: String,
+    #[serde(default)]
+    pub filter: SubscriptionFilter,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct SubscriptionRow {
+    id: i32,
+    url: String,
+    secret: String,
+    filter: String,
+    created_at: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Subscription {
+    pub id: i32,
+    pub url: String,
+    /// Only ever returned when the subscription is created.
+    pub secret: String,
+    pub filter: SubscriptionFilter,
+    pub created_at: String,
+}
+
+impl TryFrom<SubscriptionRow> for Subscription {
+    type Error = Error;
+
+    fn try_from(row: SubscriptionRow) -> Result<Self> {
+        Ok(Subscription {
+            id: row.id,
+            url: row.url,
+            secret: row.secret,
+            filter: serde_json::from_str(&row.filter)?,
+            created_at: row.created_at,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WebhookPayload<'a> {
+    pub event: &'a str,
+    pub sent_at: String,
+    pub car: &'a Car,
+}
+
+#[derive(Debug, Display, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    #[display(fmt = "pending")]
+    Pending,
+    #[display(fmt = "delivered")]
+    Delivered,
+    #[display(fmt = "failed")]
+    Failed,
+    #[display(fmt = "dead")]
+    Dead,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Delivery {
+    pub id: i32,
+    pub subscription_id: i32,
+    pub car_id: CarId,
+    pub event: String,
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub next_attempt_at: Option<String>,
+}
+
+pub fn sign(secret: &str, timestamp: &str, body: &str) -> Result<String> {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).map_err(|e| Error::from(e.to_string()))?;
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+pub fn generate_secret() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// Posts `payload` with the event name, a timestamp and an HMAC of both so receivers can check
+/// it came from us: `X-Failcat-Signature: sha256=hex(hmac(secret, "{timestamp}.{payload}"))`.
+pub async fn post_signed(
+    url: &str,
+    secret: &str,
+    event: &str,
+    mut headers: HeaderMap,
+    payload: &str,
+) -> Result<u16> {
+    let timestamp = Utc::now().timestamp().to_string();
+    let signature = sign(secret, &timestamp, payload)?;
+    headers.insert(
+        "X-Failcat-Event",
+        HeaderValue::from_str(event).expect("couldn't set header"),
+    );
+    headers.insert(
+        "X-Failcat-Timestamp",
+        HeaderValue::from_str(&timestamp).expect("couldn't set header"),
+    );
+    headers.insert(
+        "X-Failcat-Signature",
+        HeaderValue::from_str(&format!("sha256={signature}")).expect("couldn't set header"),
+    );
+    post_json(url, headers, payload.to_string()).await
+}
+
+pub struct SubscriptionRepository {
+    d1: Database,
+}
+
+impl SubscriptionRepository {
+    pub fn new(d1: Database) -> Self {
+        SubscriptionRepository { d1 }
+    }
+
+    pub async fn create(&self, new: NewSubscription) -> Result<Subscription> {
+        let secret = generate_secret();
+        let statement = self.d1.prepare(
+            "INSERT INTO subscriptions (url, secret, filter, created_at) VALUES (?, ?, ?, ?) RETURNING *",
+        );
+        let query = statement.bind(&[
+            new.url.into(),
+            secret.into(),
+            serde_json::to_string(&new.filter)?.into(),
+            Utc::now().to_rfc3339().into(),
+        ])?;
+        match query.first::<SubscriptionRow>(None).await? {
+            Some(row) => row.try_into(),
+            None => Err("No subscription created".into()),
+        }
+    }
+
+    pub async fn get(&self, id: i32) -> Result<Option<Subscription>> {
+        let statement = self.d1.prepare("SELECT * FROM subscriptions WHERE id = ?");
+        let query = statement.bind(&[id.into()])?;
+        query
+            .first::<SubscriptionRow>(None)
+            .await?
+            .map(Subscription::try_from)
+            .transpose()
+    }
+
+    pub async fn get_active(&self) -> Result<Vec<Subscription>> {
+        let statement = self
+            .d1
+            .prepare("SELECT * FROM subscriptions WHERE active = 1");
+        statement
+            .all()
+            .await?
+            .results::<SubscriptionRow>()?
+            .into_iter()
+            .map(Subscription::try_from)
+            .collect()
+    }
+
+    /// Records a delivery to each subscription as pending and due straight away, for the next
+    /// sender to pick up. All or none are written.
+    async fn log_deliveries(
+        &self,
+        subscription_ids: &[i32],
+        car_id: CarId,
+        payload: &str,
+    ) -> Result<usize> {
+        if subscription_ids.is_empty() {
+            return Ok(0);
+        }
+        let now = Utc::now().to_rfc3339();
+        let mut statements = vec![];
+        for subscription_id in subscription_ids {
+            let statement = self.d1.prepare(
+                "INSERT INTO webhook_deliveries (subscription_id, car_id, event, payload, status, created_at, next_attempt_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            );
+            statements.push(statement.bind(&[
+                (*subscription_id).into(),
+                car_id.0.into(),
+                CAR_CREATED.into(),
+                payload.into(),
+                DeliveryStatus::Pending.to_string().into(),
+                now.clone().into(),
+                now.clone().into(),
+            ])?);
+        }
+        self.d1.batch(statements).await?;
+        Ok(subscription_ids.len())
+    }
+
+    async fn record_attempt(
+        &self,
+        delivery_id: i32,
+        status: DeliveryStatus,
+        response_status: Option<u16>,
+        error: Option<String>,
+        next_attempt_at: Option<String>,
+    ) -> Result<()> {
+        let statement = self.d1.prepare(
+            "UPDATE webhook_deliveries SET status = ?, attempts = attempts + 1, response_status = ?, last_error = ?, next_attempt_at = ? WHERE id = ?",
+        );
+        let query = statement.bind(&[
+            status.to_string().into(),
+            response_status.map(i32::from).into(),
+            error.into(),
+            next_attempt_at.into(),
+            delivery_id.into(),
+        ])?;
+        query.run().await?;
+        Ok(())
+    }
+
+    pub async fn get_deliveries(&self, subscription_id: i32) -> Result<Vec<Delivery>> {
+        let statement = self.d1.prepare(
+            "SELECT * FROM webhook_deliveries WHERE subscription_id = ? ORDER BY id DESC LIMIT 100",
+        );
+        let query = statement.bind(&[subscription_id.into()])?;
+        query.all().await?.results::<Delivery>()
+    }
+
+    /// New deliveries and failed ones whose backoff is up, oldest first.
+    async fn due_deliveries(&self) -> Result<Vec<Delivery>> {
+        let statement = self.d1.prepare(
+            "SELECT * FROM webhook_deliveries WHERE status IN ('pending', 'failed') AND next_attempt_at <= ? ORDER BY id LIMIT ?",
+        );
+        let query =
+            statement.bind(&[Utc::now().to_rfc3339().into(), DELIVERY_BATCH_SIZE.into()])?;
+        query.all().await?.results::<Delivery>()
+    }
+
+    /// Pushes a due delivery's next attempt out by the lease, so a sender running at the same
+    /// time skips it. `false` when another sender got there first.
+    async fn claim(&self, delivery: &Delivery) -> Result<bool> {
+        let lease = Utc::now() + chrono::Duration::minutes(DELIVERY_LEASE_MINUTES);
+        let statement = self.d1.prepare(
+            "UPDATE webhook_deliveries SET next_attempt_at = ? WHERE id = ? AND next_attempt_at = ? RETURNING id",
+        );
+        let query = statement.bind(&[
+            lease.to_rfc3339().into(),
+            delivery.id.into(),
+            delivery.next_attempt_at.clone().into(),
+        ])?;
+        Ok(query.first::<i32>(Some("id")).await?.is_some())
+    }
+
+    /// Makes one attempt at a delivery and logs the outcome, scheduling a retry with
+    /// exponential backoff when it fails.
+    async fn send(
+        &self,
+        subscription: &Subscription,
+        delivery: &Delivery,
+    ) -> Result<DeliveryStatus> {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Failcat-Delivery", HeaderValue::from(delivery.id));
+
+        let sent = post_signed(
+            &subscription.url,
+            &subscription.secret,
+            &delivery.event,
+            headers,
+            &delivery.payload,
+        )
+        .await;
+        let (response_status, error) = match sent {
+            Ok(status) if (200..300).contains(&status) => {
+                self.record_attempt(
+                    delivery.id,
+                    DeliveryStatus::Delivered,
+                    Some(status),
+                    None,
+                    None,
+                )
+                .await?;
+                return Ok(DeliveryStatus::Delivered);
+            }
+            Ok(status) => (Some(status), format!("subscriber answered {status}")),
+            Err(e) => (None, e.to_string()),
+        };
+
+        let attempts = delivery.attempts + 1;
+        let (status, next_attempt_at) = if attempts >= MAX_ATTEMPTS {
+            (DeliveryStatus::Dead, None)
+        } else {
+            let backoff = chrono::Duration::minutes(1i64 << attempts.min(10));
+            (
+                DeliveryStatus::Failed,
+                Some((Utc::now() + backoff).to_rfc3339()),
+            )
+        };
+        self.record_attempt(
+            delivery.id,
+            status,
+            response_status,
+            Some(error),
+            next_attempt_at,
+        )
+        .await?;
+        Ok(status)
+    }
+}
+
+/// Records a `car.created` delivery for every subscription the car matches and hands back how
+/// many. Nothing is sent here, so a slow subscriber can't hold up the scrape;
+/// `send_due_deliveries` does the posting.
+pub async fn queue_car_created(env: &Env, car: &Car) -> Result<usize> {
+    let car_id = match car.id {
+        Some(car_id) => car_id,
+        None => return Err("Can't deliver a car that hasn't been saved".into()),
+    };
+    let repo = SubscriptionRepository::new(env.d1("failcat_db")?);
+    let payload = serde_json::to_string(&WebhookPayload {
+        event: CAR_CREATED,
+        sent_at: Utc::now().to_rfc3339(),
+        car,
+    })?;
+
+    let subscription_ids: Vec<i32> = repo
+        .get_active()
+        .await?
+        .into_iter()
+        .filter(|subscription| subscription.filter.matches(car))
+        .map(|subscription| subscription.id)
+        .collect();
+    repo.log_deliveries(&subscription_ids, car_id, &payload)
+        .await
+}
+
+/// Sends new deliveries and retries failed ones that are due, one attempt each. Runs after a
+/// request or batch that queued deliveries and on every cron trigger.
+pub async fn send_due_deliveries(env: &Env) -> Result<i32> {
+    let repo = SubscriptionRepository::new(env.d1("failcat_db")?);
+    let mut sent = 0;
+    for delivery in repo.due_deliveries().await? {
+        if !repo.claim(&delivery).await? {
+            continue;
+        }
+        let subscription = match repo.get(delivery.subscription_id).await? {
+            Some(subscription) => subscription,
+            None => continue,
+        };
+        let status = repo.send(&subscription, &delivery).await?;
+        console_debug!(
+            "delivery {} to subscription {}: {}",
+            delivery.id,
+            subscription.id,
+            status
+        );
+        sent += 1;
+    }
+    Ok(sent)
+}
@@ -0,0 +1,295 @@
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::{Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+use worker::Method;
+
+use crate::auth::{self, ApiKey, CreatedApiKey, KeyUsage, NewApiKey};
+use crate::common::ScrapeResponse;
+use crate::coordinator::CoordinatorStatus;
+use crate::envelope::{ApiError, Meta, V1_PREFIX};
+use crate::jobs::ScrapeJob;
+use crate::models::{
+    BrokenSticker, Car, CarHistoryEntry, CarId, CarModelSummary, CarOptions, CarQuery,
+    ColorCatalog, Dealer, DealerStats, ReconcileQuery, ReconcileReport, SearchQuery, SearchResult,
+};
+use crate::notify::watch::{NewWatch, Watch, WatchStatus};
+use crate::notify::webhooks::{Delivery, NewSubscription, Subscription};
+use crate::scraper::reparse::{ReparseQuery, ReparseReport};
+
+const JSON: &str = "application/json";
+const TEXT: &str = "text/plain";
+
+/// One route in the spec. Every route registered in `lib.rs` needs one of these, the tests
+/// below fail otherwise.
+struct Operation {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    query: Option<SchemaObject>,
+    body: Option<Schema>,
+    content_type: &'static str,
+    response: Option<Schema>,
+}
+
+fn op(method: &'static str, path: &'static str, summary: &'static str) -> Operation {
+    Operation {
+        method,
+        path,
+        summary,
+        query: None,
+        body: None,
+        content_type: JSON,
+        response: None,
+    }
+}
+
+impl Operation {
+    /// Query parameters, one per field of `T`.
+    fn query<T: JsonSchema>(mut self, gen: &mut SchemaGenerator) -> Self {
+        self.query = Some(T::json_schema(gen).into_object());
+        self
+    }
+
+    fn body<T: JsonSchema>(mut self, gen: &mut SchemaGenerator) -> Self {
+        self.body = Some(gen.subschema_for::<T>());
+        self
+    }
+
+    fn returns<T: JsonSchema>(mut self, gen: &mut SchemaGenerator) -> Self {
+        self.response = Some(gen.subschema_for::<T>());
+        self
+    }
+
+    fn produces(mut self, content_type: &'static str) -> Self {
+        self.content_type = content_type;
+        self
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        let mut parameters: Vec<Value> = self
+            .path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix(':'))
+            .map(|name| {
+                let kind = match name {
+                    "id" | "n" | "serial" | "serial_number" => "integer",
+                    _ => "string",
+                };
+                json!({"name": name, "in": "path", "required": true, "schema": {"type": kind}})
+            })
+            .collect();
+        if let Some(object) = self.query.as_ref().and_then(|query| query.object.as_ref()) {
+            parameters.extend(object.properties.iter().map(|(name, schema)| {
+                json!({
+                    "name": name,
+                    "in": "query",
+                    "required": object.required.contains(name),
+                    "schema": schema,
+                })
+            }));
+        }
+        parameters
+    }
+
+    /// JSON and text responses come wrapped in an `Envelope` under `/v1`, files and feeds don't.
+    fn content(&self, error: &Schema, meta: &Schema) -> Value {
+        let data = match (self.content_type, &self.response) {
+            (JSON, Some(schema)) => json!(schema),
+            (TEXT, _) => json!({"type": "string"}),
+            (JSON, None) => json!({}),
+            (other, _) => return json!({other: {"schema": {}}}),
+        };
+        json!({JSON: {"schema": {
+            "type": "object",
+            "properties": {
+                "data": data,
+                "error": {"allOf": [error], "nullable": true},
+                "meta": meta,
+            },
+        }}})
+    }
+
+    fn to_json(&self, error: &Schema, meta: &Schema) -> Value {
+        let mut operation = json!({
+            "summary": self.summary,
+            "parameters": self.parameters(),
+            "responses": {
+                "200": {"description": "OK", "content": self.content(error, meta)},
+                "default": {"description": "Error", "content": self.content(error, meta)},
+            },
+        });
+        if let Some(body) = &self.body {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": {JSON: {"schema": body}},
+            });
+        }
+        let method = match self.method {
+            "post" => Method::Post,
+            "delete" => Method::Delete,
+            _ => Method::Get,
+        };
+        if let Some(scope) = auth::required_scope(&method, self.path) {
+            operation["security"] = json!([{"bearer": []}, {"apiKey": []}]);
+            operation["description"] = json!(format!("Needs an API key with the {scope} scope."));
+        }
+        operation
+    }
+
+    fn openapi_path(&self) -> String {
+        openapi_path(self.path)
+    }
+}
+
+/// `/car/:id` as OpenAPI writes it, `/car/{id}`.
+fn openapi_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(name) => format!("{{{name}}}"),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn operations(gen: &mut SchemaGenerator) -> Vec<Operation> {
+    vec![
+        op("get", "/", "Health check").produces(TEXT),
+        op("get", "/worker-version", "The workers-rs version").produces(TEXT),
+        op("get", "/openapi.json", "This document"),
+        op("get", "/car/:id", "A car by id").returns::<Car>(gen),
+        op("get", "/car/:id/options", "Options and pricing from a car's sticker")
+            .returns::<CarOptions>(gen),
+        op("get", "/car/:id/history", "Field changes recorded for a car")
+            .returns::<Vec<CarHistoryEntry>>(gen),
+        op("post", "/car/:id/rescrape", "Fetch a car's sticker from upstream again"),
+        op("get", "/cars", "Cars matching the filters")
+            .query::<CarQuery>(gen)
+            .returns::<Vec<Car>>(gen),
+        op("get", "/feed.atom", "Newly found cars as Atom").produces("application/atom+xml"),
+        op("get", "/feed.rss", "Newly found cars as RSS").produces("application/rss+xml"),
+        op("get", "/vinlookup/:vin", "Check a VIN against upstream").produces(TEXT),
+        op("get", "/serial/:serial", "The sticker for a serial, or its candidate VINs")
+            .produces("application/pdf"),
+        op("post", "/serial/:serial", "Scrape and save the car for a serial")
+            .returns::<CarId>(gen),
+        op("get", "/scrape_next", "Scrape the next serial from the coordinator")
+            .returns::<Option<CarId>>(gen),
+        op("get", "/scrape_next/:n", "Scrape n serials past the highest known one")
+            .returns::<Option<CarId>>(gen),
+        op("get", "/scrape_below/:n", "Scrape the first unknown serial below n")
+            .returns::<ScrapeResponse>(gen),
+        op("get", "/scrape_above/:n", "Scrape the first unknown serial above n")
+            .returns::<ScrapeResponse>(gen),
+        op("get", "/scrape/:serial_number", "Scrape one serial")
+            .returns::<Option<CarId>>(gen),
+        op("post", "/scrape/jobs", "Queue scrape jobs")
+            .body::<Vec<ScrapeJob>>(gen)
+            .returns::<usize>(gen),
+        op("get", "/admin/coordinator", "Scrape coordinator cursor, queue and leases")
+            .returns::<CoordinatorStatus>(gen),
+        op("get", "/window-sticker/:vin", "A window sticker PDF").produces("application/pdf"),
+        op("get", "/stickers", "Archived stickers for a serial"),
+        op("get", "/stickers/:vin/text", "Text extracted from a sticker").produces(TEXT),
+        op("get", "/stickers/:vin", "A sticker PDF, with conditional requests")
+            .produces("application/pdf"),
+        op("post", "/graphql", "GraphQL over cars, dealers, models and scraper logs"),
+        op("get", "/search", "Cars and dealers matching free text, best first")
+            .query::<SearchQuery>(gen)
+            .returns::<Vec<SearchResult>>(gen),
+        op("get", "/dealers", "Dealers with their car counts").returns::<Vec<Dealer>>(gen),
+        op("post", "/admin/reparse", "Reparse stored stickers with the current parser")
+            .query::<ReparseQuery>(gen)
+            .returns::<ReparseReport>(gen),
+        op("post", "/admin/reconcile", "Reconcile the KV car cache with D1")
+            .query::<ReconcileQuery>(gen)
+            .returns::<ReconcileReport>(gen),
+        op("post", "/keys", "Create an API key")
+            .body::<NewApiKey>(gen)
+            .returns::<CreatedApiKey>(gen),
+        op("get", "/keys", "All API keys").returns::<Vec<ApiKey>>(gen),
+        op("delete", "/keys/:id", "Revoke an API key").returns::<ApiKey>(gen),
+        op("get", "/keys/:id/usage", "Daily requests made with an API key")
+            .returns::<Vec<KeyUsage>>(gen),
+        op("get", "/colors", "Known exterior and interior colors").returns::<ColorCatalog>(gen),
+        op("get", "/models", "Known car models").returns::<Vec<CarModelSummary>>(gen),
+        op("post", "/subscriptions", "Subscribe a webhook to new cars")
+            .body::<NewSubscription>(gen)
+            .returns::<Subscription>(gen),
+        op("get", "/subscriptions/:id/deliveries", "Recent deliveries to a webhook")
+            .returns::<Vec<Delivery>>(gen),
+        op("post", "/watch", "Watch a VIN or serial")
+            .body::<NewWatch>(gen)
+            .returns::<Watch>(gen),
+        op("get", "/watch/:id", "A watch").returns::<WatchStatus>(gen),
+        op("get", "/broken", "Serials quarantined for broken stickers")
+            .returns::<Vec<BrokenSticker>>(gen),
+        op("get", "/dealers/:code/stats", "Stats for one dealer").returns::<DealerStats>(gen),
+    ]
+}
+
+/// The OpenAPI 3 document for every `/v1` route, with schemas derived from the types the
+/// routes take and return. The unversioned aliases answer the same, minus the envelope.
+pub fn spec() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let error = gen.subschema_for::<ApiError>();
+    let meta = gen.subschema_for::<Meta>();
+    let mut paths = Map::new();
+    for operation in operations(&mut gen) {
+        let path = paths
+            .entry(operation.openapi_path())
+            .or_insert_with(|| json!({}));
+        path[operation.method] = operation.to_json(&error, &meta);
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "failcat",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{"url": V1_PREFIX}],
+        "paths": paths,
+        "components": {
+            "schemas": gen.take_definitions(),
+            "securitySchemes": {
+                "bearer": {"type": "http", "scheme": "bearer"},
+                "apiKey": {"type": "apiKey", "in": "header", "name": "X-Api-Key"},
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    #[test]
+    fn spec_documents_exactly_the_routes_served() {
+        let served: BTreeSet<(String, String)> = crate::routes()
+            .table()
+            .iter()
+            .map(|(method, path)| (method.to_string(), openapi_path(path)))
+            .collect();
+        let spec = spec();
+        let documented: BTreeSet<(String, String)> = spec["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .flat_map(|(path, operations)| {
+                let methods = operations.as_object().unwrap().keys();
+                methods.map(move |method| (method.clone(), path.clone()))
+            })
+            .collect();
+
+        let missing: Vec<_> = served.difference(&documented).collect();
+        assert!(
+            missing.is_empty(),
+            "routes without a spec entry: {missing:?}"
+        );
+        let stale: Vec<_> = documented.difference(&served).collect();
+        assert!(stale.is_empty(), "spec entries without a route: {stale:?}");
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use reqwest_wasm::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+/// A token bucket: `capacity` requests in a burst, refilled at `refill_per_minute`.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub capacity: f64,
+    pub refill_per_minute: f64,
+}
+
+/// Routes are limited in groups that share one bucket per client. Only the routes that can end
+/// up calling upstream are limited, everything else is served from our own stores.
+pub fn route_group(method: &Method, path: &str) -> Option<&'static str> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        (Method::Options, _) => None,
+        (_, ["vinlookup", _])
+        | (_, ["window-sticker", _])
+        | (_, ["stickers", _])
+        | (Method::Get, ["serial", _]) => Some("upstream"),
+        (_, ["scrape_next", ..])
+        | (_, ["scrape_below", _])
+        | (_, ["scrape_above", _])
+        | (_, ["scrape", _])
+        | (Method::Post, ["serial", _])
+        | (Method::Post, ["car", _, "rescrape"]) => Some("scrape"),
+        _ => None,
+    }
+}
+
+fn default_limit(group: &str) -> Limit {
+    match group {
+        "scrape" => Limit {
+            capacity: 30.0,
+            refill_per_minute: 6.0,
+        },
+        _ => Limit {
+            capacity: 10.0,
+            refill_per_minute: 2.0,
+        },
+    }
+}
+
+/// The limit for a group, overridable through the `RATE_LIMITS` var, e.g.
+/// `{"upstream": {"capacity": 10, "refill_per_minute": 2}}`.
+pub fn limit_for(env: &Env, group: &str) -> Limit {
+    env.var("RATE_LIMITS")
+        .ok()
+        .and_then(|limits| {
+            serde_json::from_str::<HashMap<String, Limit>>(&limits.to_string())
+                .map_err(|e| console_error!("couldn't parse RATE_LIMITS: {:?}", e))
+                .ok()
+        })
+        .and_then(|limits| limits.get(group).copied())
+        .unwrap_or_else(|| default_limit(group))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: f64,
+}
+
+impl Bucket {
+    fn full(limit: &Limit, now: f64) -> Self {
+        Bucket {
+            tokens: limit.capacity,
+            updated_at: now,
+        }
+    }
+
+    /// Takes a token if there is one, otherwise hands back the seconds until there will be.
+    fn take(&mut self, limit: &Limit, now: f64) -> std::result::Result<(), u64> {
+        let per_ms = limit.refill_per_minute / 60_000.0;
+        let elapsed = (now - self.updated_at).max(0.0);
+        self.tokens = (self.tokens + elapsed * per_ms).min(limit.capacity);
+        self.updated_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if per_ms <= 0.0 {
+            Err(u64::MAX)
+        } else {
+            Err(((1.0 - self.tokens) / per_ms / 1000.0).ceil() as u64)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TakeRequest {
+    pub group: String,
+    pub limit: Limit,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TakeResponse {
+    pub allowed: bool,
+    pub retry_after: Option<u64>,
+}
+
+/// One instance per client, holding that client's buckets. Buckets only live in memory, so an
+/// evicted limiter starts the client over with full buckets, which errs on the side of letting
+/// requests through.
+#[durable_object]
+pub struct RateLimiter {
+    buckets: HashMap<String, Bucket>,
+}
+
+#[durable_object]
+impl DurableObject for RateLimiter {
+    fn new(_state: State, _env: Env) -> Self {
+        RateLimiter {
+            buckets: HashMap::new(),
+        }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let take = req.json::<TakeRequest>().await?;
+        let now = Date::now().as_millis() as f64;
+        let bucket = self
+            .buckets
+            .entry(take.group)
+            .or_insert_with(|| Bucket::full(&take.limit, now));
+        let response = match bucket.take(&take.limit, now) {
+            Ok(()) => TakeResponse {
+                allowed: true,
+                retry_after: None,
+            },
+            Err(retry_after) => TakeResponse {
+                allowed: false,
+                retry_after: Some(retry_after),
+            },
+        };
+        Response::from_json(&response)
+    }
+}
+
+async fn take(env: &Env, client: &str, group: &str) -> Result<TakeResponse> {
+    let take = TakeRequest {
+        group: group.to_string(),
+        limit: limit_for(env, group),
+    };
+    let stub = env
+        .durable_object("RATE_LIMITER")?
+        .id_from_name(client)?
+        .get_stub()?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_body(Some(serde_json::to_string(&take)?.into()));
+    let request = Request::new_with_init("https://rate-limiter/take", &init)?;
+    stub.fetch_with_request(request).await?.json().await
+}
+
+/// Runs ahead of the router: takes a token from the client's bucket for the route's group and
+/// hands back a 429 with `Retry-After` when it's empty. The limiter failing lets the request
+/// through rather than taking the site down with it.
+pub async fn check(req: &Request, env: &Env, client: &str) -> Result<Option<Response>> {
+    let group = match route_group(&req.method(), &req.path()) {
+        Some(group) => group,
+        None => return Ok(None),
+    };
+    let retry_after = match take(env, client, group).await {
+        Ok(TakeResponse { allowed: true, .. }) => return Ok(None),
+        Ok(TakeResponse { retry_after, .. }) => retry_after.unwrap_or(60),
+        Err(e) => {
+            console_error!("rate limiter failed for {}: {:?}", client, e);
+            return Ok(None);
+        }
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    let response = Response::error("Too many requests", 429)?.with_headers(headers.into());
+    Ok(Some(response))
+}
@@ -0,0 +1,78 @@
+use std::future::Future;
+
+use worker::*;
+
+type Handler = fn(Request, RouteContext<()>) -> Result<Response>;
+
+/// The router, along with the method and path of every route registered on it. The OpenAPI
+/// tests check `openapi::spec()` against that table, so the two can't drift apart.
+pub struct Routes<'a> {
+    router: Router<'a, ()>,
+    table: Vec<(&'static str, &'static str)>,
+}
+
+impl Default for Routes<'_> {
+    fn default() -> Self {
+        Routes {
+            router: Router::new(),
+            table: vec![],
+        }
+    }
+}
+
+impl<'a> Routes<'a> {
+    pub fn get(mut self, path: &'static str, handler: Handler) -> Self {
+        self.table.push(("get", path));
+        self.router = self.router.get(path, handler);
+        self
+    }
+
+    pub fn get_async<T>(
+        mut self,
+        path: &'static str,
+        handler: fn(Request, RouteContext<()>) -> T,
+    ) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'a,
+    {
+        self.table.push(("get", path));
+        self.router = self.router.get_async(path, handler);
+        self
+    }
+
+    pub fn post_async<T>(
+        mut self,
+        path: &'static str,
+        handler: fn(Request, RouteContext<()>) -> T,
+    ) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'a,
+    {
+        self.table.push(("post", path));
+        self.router = self.router.post_async(path, handler);
+        self
+    }
+
+    pub fn delete_async<T>(
+        mut self,
+        path: &'static str,
+        handler: fn(Request, RouteContext<()>) -> T,
+    ) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'a,
+    {
+        self.table.push(("delete", path));
+        self.router = self.router.delete_async(path, handler);
+        self
+    }
+
+    /// Every route as `(method, path)`, in the order they were registered.
+    #[cfg(test)]
+    pub fn table(&self) -> &[(&'static str, &'static str)] {
+        &self.table
+    }
+
+    pub async fn run(self, req: Request, env: Env) -> Result<Response> {
+        self.router.run(req, env).await
+    }
+}
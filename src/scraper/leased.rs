@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::coordinator::{Lease, ScrapeCoordinator, WorkKind, WorkOutcome};
+use crate::models::{highest_serial, Car, CarId, CarStatus, SerialNumber};
+use crate::notify;
+
+use super::recheck::{recheck_serial, RecheckOutcome};
+use super::vinlookup::{self, scrape_serial};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct DrainReport {
+    pub found: Vec<SerialNumber>,
+    pub not_found: Vec<SerialNumber>,
+    pub failed: Vec<SerialNumber>,
+    /// Set when upstream limits stopped the run early.
+    pub limited: bool,
+}
+
+fn is_new_car(car: &Car) -> bool {
+    car.status == CarStatus::Ok
+}
+
+/// Does the work behind one lease and reports back to the coordinator, whatever happens.
+pub async fn run_lease(
+    env: &Env,
+    coordinator: &dyn ScrapeCoordinator,
+    lease: &Lease,
+) -> Result<(WorkOutcome, Option<CarId>)> {
+    let (outcome, car) = work_lease(env, coordinator, lease).await?;
+    if let Some(car) = car.as_ref().filter(|car| is_new_car(car)) {
+        notify::car_created(env, car).await;
+    }
+    Ok((outcome, car.and_then(|car| car.id)))
+}
+
+/// `run_lease` without the notifications, for callers batching them.
+async fn work_lease(
+    env: &Env,
+    coordinator: &dyn ScrapeCoordinator,
+    lease: &Lease,
+) -> Result<(WorkOutcome, Option<Car>)> {
+    let serial = lease.item.serial_number;
+    let result = match lease.item.kind {
+        WorkKind::Recheck => match recheck_serial(env, serial).await {
+            Ok(RecheckOutcome::Recovered(car)) => Ok(Some(car)),
+            Ok(_) => Ok(None),
+            Err(e) => Err(e),
+        },
+        _ => scrape_serial(serial, env).await,
+    };
+    let outcome = match &result {
+        Ok(Some(_)) => WorkOutcome::Found,
+        Ok(None) => WorkOutcome::NotFound,
+        Err(e) if vinlookup::is_limits_exceeded(e) => WorkOutcome::LimitsExceeded,
+        Err(e) => {
+            console_error!("{} work on {} failed: {:?}", lease.item.kind, serial, e);
+            WorkOutcome::Failed
+        }
+    };
+    coordinator.complete(lease, outcome).await?;
+    result.map(|car| (outcome, car))
+}
+
+/// Leases and runs up to `limit` pieces of work, stopping early once upstream limits are hit or
+/// the forward cursor runs past the newest car. Everything found is announced in one batch at
+/// the end, so chat channels get a single message per run.
+pub async fn drain(
+    env: &Env,
+    coordinator: &dyn ScrapeCoordinator,
+    worker: &str,
+    limit: i32,
+) -> Result<DrainReport> {
+    let mut report = DrainReport::default();
+    let mut new_cars = vec![];
+    let mut lease_error = None;
+    for _ in 0..limit {
+        let floor = highest_serial(env).await + 1.into();
+        let lease = match coordinator.lease(worker, floor).await {
+            Ok(Some(lease)) => lease,
+            Ok(None) => {
+                report.limited = true;
+                break;
+            }
+            Err(e) => {
+                lease_error = Some(e);
+                break;
+            }
+        };
+        let serial = lease.item.serial_number;
+        let found = match work_lease(env, coordinator, &lease).await {
+            Ok((WorkOutcome::Found, car)) => {
+                new_cars.extend(car.filter(is_new_car));
+                report.found.push(serial);
+                true
+            }
+            Ok(_) => {
+                report.not_found.push(serial);
+                false
+            }
+            Err(e) if vinlookup::is_limits_exceeded(&e) => {
+                report.limited = true;
+                break;
+            }
+            Err(_) => {
+                report.failed.push(serial);
+                false
+            }
+        };
+        // Forward work is only handed out once the queue is empty, and a miss pulls the cursor
+        // back so the next forward lease would be this same serial again
+        if !found && lease.item.kind == WorkKind::Forward {
+            break;
+        }
+    }
+
+    notify::cars_created(env, &new_cars).await;
+    match lease_error {
+        Some(e) => Err(e),
+        None => Ok(report),
+    }
+}
@@ -1 +1,6 @@
+pub mod leased;
+pub mod recheck;
+pub mod reparse;
+pub mod rescrape;
 pub mod vinlookup;
+pub mod watched;
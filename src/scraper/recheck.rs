@@ -0,0 +1,62 @@
+use worker::*;
+
+use crate::coordinator::{WorkItem, WorkKind};
+use crate::models::{
+    store_sticker_details, BrokenStickerRepository, Car, CarStatus, CarStore, ChangeSource,
+    SerialNumber, MAX_RECHECK_ATTEMPTS,
+};
+
+#[derive(Debug)]
+pub enum RecheckOutcome {
+    /// Upstream finally had a sticker, the placeholder row now holds the real car.
+    Recovered(Car),
+    StillBroken,
+    /// Out of attempts, the car is marked broken for good.
+    GaveUp,
+}
+
+/// Quarantined serials due another look, as coordinator work.
+pub async fn due_rechecks(env: &Env, limit: i32) -> Result<Vec<WorkItem>> {
+    let quarantine = BrokenStickerRepository::new(env.d1("failcat_db")?);
+    Ok(quarantine
+        .due_for_recheck(limit)
+        .await?
+        .into_iter()
+        .map(|entry| WorkItem::new(entry.serial_number, WorkKind::Recheck))
+        .collect())
+}
+
+/// Retries one quarantined serial, swapping the placeholder row for the real car when upstream
+/// finally has a sticker for it. Upstream errors, limits included, are left to the caller.
+pub async fn recheck_serial(env: &Env, serial: SerialNumber) -> Result<RecheckOutcome> {
+    let store = CarStore::new(env)?;
+    let quarantine = BrokenStickerRepository::new(env.d1("failcat_db")?);
+
+    let found = Car::from_vinlookup(serial, env).await?;
+    if let Some(car) = found.as_ref().filter(|car| car.status == CarStatus::Ok) {
+        let (car, _) = store.save(car, ChangeSource::Scrape).await?;
+        let car_id = car.id.ok_or("Saved car has no id")?;
+        store_sticker_details(env, car_id, &car.vin).await?;
+        quarantine.resolve(serial).await?;
+        return Ok(RecheckOutcome::Recovered(car));
+    }
+
+    // A broken response already bumped the attempt count while quarantining
+    if found.is_none() {
+        quarantine.record_attempt(serial).await?;
+    }
+    let attempts = quarantine
+        .get(serial)
+        .await?
+        .map(|entry| entry.attempts)
+        .unwrap_or(MAX_RECHECK_ATTEMPTS);
+    if attempts >= MAX_RECHECK_ATTEMPTS {
+        // Serials quarantined by a sticker download never got a placeholder row
+        if store.get(serial).await?.is_some() {
+            store.set_status(serial, CarStatus::Broken).await?;
+        }
+        Ok(RecheckOutcome::GaveUp)
+    } else {
+        Ok(RecheckOutcome::StillBroken)
+    }
+}
@@ -0,0 +1,146 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::models::{
+    Car, CarStore, ChangeSource, OptionRepository, SerialNumber, StickerPricing, Vin,
+    PARSER_VERSION,
+};
+use crate::notify;
+use crate::stickers::{ParseStatus, StickerArchive, StickerTextCache};
+
+// pdf parsing is CPU heavy, so a single request only gets through a small page
+const DEFAULT_PAGE_SIZE: u32 = 25;
+const MAX_PAGE_SIZE: u32 = 100;
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ReparseQuery {
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ReparseChange {
+    pub serial_number: SerialNumber,
+    pub vin: Vin,
+    pub fields: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ReparseFailure {
+    pub vin: Vin,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct ReparseReport {
+    pub parser_version: i32,
+    pub scanned: i32,
+    pub skipped: i32,
+    pub unchanged: i32,
+    /// Stickers with no car row, or gone from the archive since the page was listed.
+    pub missing: Vec<Vin>,
+    pub changed: Vec<ReparseChange>,
+    pub failed: Vec<ReparseFailure>,
+    /// Pass back as `cursor` to continue with the next page.
+    pub cursor: Option<String>,
+}
+
+/// Re-runs the sticker parser over one page of archived stickers and writes back any rows the
+/// current parser disagrees with. Extracted text is reused from the cache where possible.
+pub async fn reparse_stickers(env: &Env, query: ReparseQuery) -> Result<ReparseReport> {
+    let d1 = env.d1("failcat_db")?;
+    let archive = StickerArchive::new(env.bucket("pdf_bucket")?);
+    let texts = StickerTextCache::new(env.d1("failcat_db")?);
+    let options = OptionRepository::new(env.d1("failcat_db")?);
+    let store = CarStore::new(env)?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let (stickers, cursor) = archive.list(query.cursor, limit).await?;
+    let mut report = ReparseReport {
+        parser_version: PARSER_VERSION,
+        cursor,
+        ..Default::default()
+    };
+
+    for info in stickers {
+        report.scanned += 1;
+        if info.is_broken() {
+            report.skipped += 1;
+            continue;
+        }
+        let cached = texts.get(&info.vin).await?;
+        let text = match cached.filter(|cached| cached.is_for(info.size)) {
+            Some(cached) => Ok(cached.text),
+            None => match archive.get(&info.vin).await? {
+                Some(sticker) => texts.text_for(&info.vin, &sticker.bytes).await,
+                // Deleted since it was listed
+                None => {
+                    report.missing.push(info.vin);
+                    continue;
+                }
+            },
+        };
+
+        let parsed = match &text {
+            Ok(text) => Car::from_sticker_text(text),
+            Err(e) => Err(e.to_string().into()),
+        };
+        let parsed = match parsed {
+            Ok(Some(car)) => car,
+            Ok(None) => {
+                report.failed.push(ReparseFailure {
+                    vin: info.vin,
+                    error: "parsed pdf as empty".to_string(),
+                });
+                continue;
+            }
+            Err(e) => {
+                archive.set_parse_status(&info, ParseStatus::Failed).await?;
+                report.failed.push(ReparseFailure {
+                    vin: info.vin,
+                    error: e.to_string(),
+                });
+                continue;
+            }
+        };
+        archive.set_parse_status(&info, ParseStatus::Parsed).await?;
+
+        let stored = match Car::from_d1_by_serial(parsed.serial_number, &d1).await? {
+            Some(stored) => stored,
+            None => {
+                report.missing.push(info.vin);
+                continue;
+            }
+        };
+
+        let fields = stored.diff(&parsed);
+        if fields.is_empty() && stored.parser_version == PARSER_VERSION {
+            report.unchanged += 1;
+            continue;
+        }
+
+        let (parsed, _) = store.save(&parsed, ChangeSource::Reparse).await?;
+        let car_id = parsed.id.ok_or("Saved car has no id")?;
+        if let Ok(text) = &text {
+            options
+                .save(car_id, &StickerPricing::from_sticker_text(text))
+                .await?;
+        }
+
+        if fields.is_empty() {
+            report.unchanged += 1;
+        } else {
+            notify::car_changed(env, &parsed, &fields).await;
+            report.changed.push(ReparseChange {
+                serial_number: parsed.serial_number,
+                vin: parsed.vin,
+                fields: fields.into_iter().map(String::from).collect(),
+            });
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,64 @@
+use serde::Serialize;
+use worker::*;
+
+use crate::models::{
+    Car, CarStore, ChangeSource, FieldChange, OptionRepository, SerialNumber, StickerPricing,
+};
+use crate::notify;
+use crate::stickers::{
+    extract_sticker_text, ParseStatus, StickerArchive, StickerTextCache, BROKEN_STICKER_SIZE,
+};
+
+use super::vinlookup;
+
+#[derive(Debug, Serialize)]
+pub struct RescrapeReport {
+    pub serial_number: SerialNumber,
+    pub changes: Vec<FieldChange>,
+}
+
+/// Fetches a fresh sticker for an already stored car, bypassing the archive, and writes back
+/// whatever changed upstream (dealer reassignments, corrected stickers) with its history.
+pub async fn rescrape(env: &Env, stored: &Car) -> Result<RescrapeReport> {
+    let vin = &stored.vin.0;
+    let data = vinlookup::vinlookup(vin).await?;
+    if (data.len() as u32) < BROKEN_STICKER_SIZE {
+        return Err(format!("upstream sent a broken sticker for {vin}").into());
+    }
+
+    let text = extract_sticker_text(&data)?;
+    let car = match Car::from_sticker_text(&text)? {
+        Some(car) => car,
+        None => return Err(format!("parsed sticker for {vin} as empty").into()),
+    };
+    if car.serial_number != stored.serial_number {
+        return Err(format!("sticker for {vin} belongs to serial {}", car.serial_number).into());
+    }
+
+    // Only replace the archived copy and cached text once the new sticker is known to parse
+    let pdf_size = data.len() as u32;
+    StickerArchive::new(env.bucket("pdf_bucket")?)
+        .put(vin, stored.serial_number, data, ParseStatus::Parsed)
+        .await?;
+    StickerTextCache::new(env.d1("failcat_db")?)
+        .put(vin, pdf_size, &text)
+        .await?;
+
+    let (car, changes) = CarStore::new(env)?
+        .save(&car, ChangeSource::Rescrape)
+        .await?;
+    let car_id = car.id.ok_or("Saved car has no id")?;
+    OptionRepository::new(env.d1("failcat_db")?)
+        .save(car_id, &StickerPricing::from_sticker_text(&text))
+        .await?;
+
+    if !changes.is_empty() {
+        let fields: Vec<&str> = changes.iter().map(|change| change.field).collect();
+        notify::car_changed(env, &car, &fields).await;
+    }
+
+    Ok(RescrapeReport {
+        serial_number: car.serial_number,
+        changes,
+    })
+}
@@ -6,6 +6,12 @@ use reqwest_wasm::header::{
 use reqwest_wasm::Client;
 use worker::*;
 
+pub const SAP_LIMITS_EXCEEDED: &str = "SAP API limits exceeded";
+
+pub fn is_limits_exceeded(error: &Error) -> bool {
+    error.to_string() == SAP_LIMITS_EXCEEDED
+}
+
 pub async fn vinlookup(vin: &str) -> Result<Vec<u8>> {
     let url = format!("https://prod.idc.kia.us/sticker/find/{vin}");
     let output_path = format!("pdfs/{vin}.pdf");
@@ -44,8 +50,8 @@ pub async fn vinlookup(vin: &str) -> Result<Vec<u8>> {
             .bytes()
             .await
             .expect("Could not get response bytes");
-        if content == *"SAP API limits exceeded" {
-            return Err(Error::from("SAP API limits exceeded"));
+        if content == *SAP_LIMITS_EXCEEDED {
+            return Err(Error::from(SAP_LIMITS_EXCEEDED));
         }
 
         println!("content length: {}", content.len());
@@ -60,7 +66,13 @@ pub async fn vinlookup(vin: &str) -> Result<Vec<u8>> {
 use itertools::{iproduct, Itertools};
 use phf::{phf_map, Map};
 
-use crate::models::{Car, CarId, ScraperLog, SerialNumber};
+use crate::models::{
+    store_sticker_details, Car, CarId, CarStatus, CarStore, InsertOutcome, ScraperLog,
+    SerialNumber,
+};
+use crate::notify;
+
+use super::recheck::{recheck_serial, RecheckOutcome};
 
 const VIN_DIGIT_POSITION_MULTIPLIER: [u32; 17] =
     [8, 7, 6, 5, 4, 3, 2, 10, 0, 9, 8, 7, 6, 5, 4, 3, 2];
@@ -176,6 +188,17 @@ pub(crate) fn is_valid_vin(vin: &str) -> bool {
     if vin.len() != 17 {
         return false;
     }
+    // get_check_sum_char panics on characters a VIN can't contain
+    if !vin
+        .chars()
+        .all(|c| VIN_DIGIT_VALUES.contains_key(c.to_string().as_str()))
+    {
+        return false;
+    }
+    // The serial number is the last six characters, and is never anything but digits
+    if !vin[11..].bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
 
     let c = get_check_sum_char(vin);
     c == vin.chars().nth(8).unwrap()
@@ -183,39 +206,52 @@ pub(crate) fn is_valid_vin(vin: &str) -> bool {
 
 pub async fn attempt_to_scrape_from_serial(
     serial: SerialNumber,
-    ctx: &RouteContext<()>,
+    env: &Env,
 ) -> Result<Option<CarId>> {
+    let car = scrape_serial(serial, env).await?;
+    if let Some(car) = car.as_ref().filter(|car| car.status == CarStatus::Ok) {
+        notify::car_created(env, car).await;
+    }
+    Ok(car.and_then(|car| car.id))
+}
+
+/// Scrapes and saves `serial`, handing back the saved car (a placeholder if only a broken
+/// sticker turned up) without notifying anyone, so a run can send its finds as one batch.
+pub async fn scrape_serial(serial: SerialNumber, env: &Env) -> Result<Option<Car>> {
     console_debug!("Attempting to scrape from serial: {}", serial);
-    let car = Car::from_kv(serial, ctx).await;
+    let store = CarStore::new(env)?;
+    match store.get(serial).await? {
+        Some(Car {
+            id,
+            status: CarStatus::Ok,
+            ..
+        }) => return Err(format!("Car already saved.: {:?}", id).into()),
+        // Placeholders for quarantined serials get another look rather than failing as saved
+        Some(_) => {
+            return match recheck_serial(env, serial).await? {
+                RecheckOutcome::Recovered(car) => Ok(Some(car)),
+                _ => Ok(None),
+            };
+        }
+        None => {}
+    }
+    console_debug!("serial not saved yet: {}", serial);
+    let car = Car::from_vinlookup(serial, env).await?;
     match car {
-        Ok(Some(Car { id, .. })) => Err(format!("Car already saved.: {:?}", id).into()),
-        Err(e) => Err(e),
-        Ok(None) => {
-            console_debug!("serial not saved to kv: {}", serial);
-            let car = Car::from_vinlookup(serial, ctx)
-                .await
-                .expect("couldn't find car");
-            match car {
-                Some(mut car) => {
-                    console_debug!("we found a car in vinlookup: {car:?}");
-                    let car_id = match car.to_d1(ctx.env.d1("failcat_db")?).await {
-                        Ok(created_id) => created_id,
-                        Err(e) => {
-                            console_error!("We received: an error {e:?}");
-                            panic!("We received: an error writing to d1");
-                        }
-                    };
-                    console_debug!("we have {car_id:?} for {car:?}");
-                    car.set_id(car_id);
-                    let kv_id = car
-                        .to_kv(ctx, Some(car_id))
-                        .await
-                        .expect("couldn't save car to database");
-                    ScraperLog::new(1, Utc::now().to_string(), "serial".to_owned(), true);
-                    Ok(Some(*kv_id))
+        Some(mut car) => {
+            console_debug!("we found a car in vinlookup: {car:?}");
+            let car_id = match store.insert(&car).await? {
+                InsertOutcome::Inserted(car_id) => car_id,
+                InsertOutcome::Conflict(car_id) => {
+                    return Err(format!("Car already saved.: {:?}", car_id).into())
                 }
-                None => Ok(None),
-            }
+            };
+            car.set_id(car_id);
+            console_debug!("we have {car_id:?} for {car:?}");
+            store_sticker_details(env, car_id, &car.vin).await?;
+            ScraperLog::new(1, Utc::now().to_string(), "serial".to_owned(), true);
+            Ok(Some(car))
         }
+        None => Ok(None),
     }
 }
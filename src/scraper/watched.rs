@@ -0,0 +1,16 @@
+use worker::*;
+
+use crate::coordinator::{WorkItem, WorkKind};
+use crate::notify::watch::WatchRepository;
+
+/// Watched serials that haven't turned up yet, as coordinator work. They're marked checked
+/// here so the next run starts with the ones that have waited longest.
+pub async fn due_watched(env: &Env, limit: i32) -> Result<Vec<WorkItem>> {
+    let watches = WatchRepository::new(env.d1("failcat_db")?);
+    let mut items = vec![];
+    for serial in watches.due_serials(limit).await? {
+        watches.touch(serial).await?;
+        items.push(WorkItem::new(serial, WorkKind::Watched));
+    }
+    Ok(items)
+}
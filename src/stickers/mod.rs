@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use chrono::{DateTime, TimeZone, Utc};
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::models::{BrokenStickerRepository, SerialNumber, Vin};
+use crate::scraper::vinlookup::{self, get_possible_vins_from_serial};
+
+mod text;
+pub use text::*;
+
+// Upstream answers unknown VINs with a short error body instead of a 404
+pub const BROKEN_STICKER_SIZE: u32 = 100;
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParseStatus {
+    #[display(fmt = "unparsed")]
+    Unparsed,
+    #[display(fmt = "parsed")]
+    Parsed,
+    #[display(fmt = "failed")]
+    Failed,
+    #[display(fmt = "broken")]
+    Broken,
+}
+
+impl FromStr for ParseStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "unparsed" => Ok(ParseStatus::Unparsed),
+            "parsed" => Ok(ParseStatus::Parsed),
+            "failed" => Ok(ParseStatus::Failed),
+            "broken" => Ok(ParseStatus::Broken),
+            other => Err(format!("unknown parse status: {other}")),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StickerInfo {
+    pub vin: Vin,
+    pub serial_number: SerialNumber,
+    pub size: u32,
+    pub etag: String,
+    pub uploaded: DateTime<Utc>,
+    pub fetched_at: Option<String>,
+    pub parse_status: ParseStatus,
+}
+
+impl StickerInfo {
+    fn from_object(object: &Object) -> Self {
+        // Objects stored before we wrote custom metadata have none, so fall back to the key
+        let metadata = object.custom_metadata().unwrap_or_default();
+        let vin = Vin(object.key());
+        let serial_number = metadata
+            .get("serial")
+            .and_then(|serial| serial.parse::<i32>().ok())
+            .map(SerialNumber)
+            .or_else(|| vin.serial_number())
+            .unwrap_or(SerialNumber(0));
+        let parse_status = metadata
+            .get("parse_status")
+            .and_then(|status| status.parse().ok())
+            .unwrap_or(if object.size() < BROKEN_STICKER_SIZE {
+                ParseStatus::Broken
+            } else {
+                ParseStatus::Unparsed
+            });
+        Self {
+            vin,
+            serial_number,
+            size: object.size(),
+            etag: object.http_etag(),
+            uploaded: Utc
+                .timestamp_millis_opt(object.uploaded().as_millis() as i64)
+                .unwrap(),
+            fetched_at: metadata.get("fetched_at").cloned(),
+            parse_status,
+        }
+    }
+
+    pub fn is_broken(&self) -> bool {
+        self.size < BROKEN_STICKER_SIZE
+    }
+
+    /// When the sticker was downloaded. Rewriting metadata re-uploads the object, so `uploaded`
+    /// only says when it was last touched.
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        self.fetched_at
+            .as_deref()
+            .and_then(|fetched_at| DateTime::parse_from_rfc3339(fetched_at).ok())
+            .map(|fetched_at| fetched_at.with_timezone(&Utc))
+            .unwrap_or(self.uploaded)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StickerQuery {
+    pub serial: SerialNumber,
+}
+
+pub struct Sticker {
+    pub info: StickerInfo,
+    pub bytes: Vec<u8>,
+}
+
+pub struct StickerArchive {
+    bucket: Bucket,
+}
+
+impl StickerArchive {
+    pub fn new(bucket: Bucket) -> Self {
+        StickerArchive { bucket }
+    }
+
+    pub async fn head(&self, vin: &str) -> worker::Result<Option<StickerInfo>> {
+        let object = self.bucket.head(vin).await?;
+        Ok(object.as_ref().map(StickerInfo::from_object))
+    }
+
+    pub async fn get(&self, vin: &str) -> worker::Result<Option<Sticker>> {
+        let object = match self.bucket.get(vin).execute().await? {
+            Some(object) => object,
+            None => return Ok(None),
+        };
+        let info = StickerInfo::from_object(&object);
+        let bytes = match object.body() {
+            Some(body) => body.bytes().await?,
+            None => vec![],
+        };
+        Ok(Some(Sticker { info, bytes }))
+    }
+
+    /// Archives a sticker. Broken bodies are refused, they'd be served as a PDF from then on.
+    pub async fn put(
+        &self,
+        vin: &str,
+        serial_number: SerialNumber,
+        data: Vec<u8>,
+        parse_status: ParseStatus,
+    ) -> worker::Result<StickerInfo> {
+        self.store(
+            vin,
+            serial_number,
+            data,
+            parse_status,
+            Utc::now().to_rfc3339(),
+        )
+        .await
+    }
+
+    /// R2 can't update metadata in place, so this re-uploads the sticker, keeping when it was
+    /// fetched. Nothing is written when the status is already right.
+    pub async fn set_parse_status(
+        &self,
+        info: &StickerInfo,
+        parse_status: ParseStatus,
+    ) -> worker::Result<()> {
+        if info.parse_status == parse_status {
+            return Ok(());
+        }
+        let sticker = match self.get(&info.vin).await? {
+            Some(sticker) => sticker,
+            None => return Ok(()),
+        };
+        let fetched_at = info
+            .fetched_at
+            .clone()
+            .unwrap_or_else(|| info.uploaded.to_rfc3339());
+        self.store(
+            &info.vin,
+            info.serial_number,
+            sticker.bytes,
+            parse_status,
+            fetched_at,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn store(
+        &self,
+        vin: &str,
+        serial_number: SerialNumber,
+        data: Vec<u8>,
+        parse_status: ParseStatus,
+        fetched_at: String,
+    ) -> worker::Result<StickerInfo> {
+        if (data.len() as u32) < BROKEN_STICKER_SIZE {
+            return Err(format!("refusing to archive a broken sticker for {vin}").into());
+        }
+        let metadata = HashMap::from([
+            ("serial".to_string(), serial_number.to_string()),
+            ("fetched_at".to_string(), fetched_at),
+            ("size".to_string(), data.len().to_string()),
+            ("parse_status".to_string(), parse_status.to_string()),
+        ]);
+        let object = self
+            .bucket
+            .put(vin, data)
+            .custom_metadata(metadata)
+            .execute()
+            .await?;
+        Ok(StickerInfo::from_object(&object))
+    }
+
+    pub async fn delete(&self, vin: &str) -> worker::Result<()> {
+        self.bucket.delete(vin).await
+    }
+
+    /// Serves the sticker from the bucket, only going upstream (and archiving the result) on a miss.
+    /// Broken upstream responses are quarantined instead of archived.
+    pub async fn fetch(
+        &self,
+        vin: &str,
+        quarantine: &BrokenStickerRepository,
+    ) -> worker::Result<Sticker> {
+        if let Some(sticker) = self.get(vin).await? {
+            return Ok(sticker);
+        }
+        let serial_number = Vin(vin.to_string())
+            .serial_number()
+            .ok_or_else(|| format!("{vin} isn't a valid VIN"))?;
+        let data = vinlookup::vinlookup(vin).await?;
+        if (data.len() as u32) < BROKEN_STICKER_SIZE {
+            quarantine.quarantine(serial_number, vin, &data).await?;
+            return Err(format!("upstream sent a broken sticker for {vin}").into());
+        }
+        let info = self
+            .put(vin, serial_number, data.clone(), ParseStatus::Unparsed)
+            .await?;
+        Ok(Sticker { info, bytes: data })
+    }
+
+    /// One page of the archive, with the cursor for the next page if there is one.
+    pub async fn list(
+        &self,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> worker::Result<(Vec<StickerInfo>, Option<String>)> {
+        let mut list = self
+            .bucket
+            .list()
+            .limit(limit)
+            .include(vec![Include::CustomMetadata]);
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let objects = list.execute().await?;
+        let stickers = objects
+            .objects()
+            .iter()
+            .map(StickerInfo::from_object)
+            .collect();
+        let cursor = if objects.truncated() {
+            objects.cursor()
+        } else {
+            None
+        };
+        Ok((stickers, cursor))
+    }
+
+    pub async fn list_for_serial(&self, serial: &SerialNumber) -> worker::Result<Vec<StickerInfo>> {
+        let mut stickers = vec![];
+        for vin in get_possible_vins_from_serial(serial) {
+            if let Some(info) = self.head(&vin).await? {
+                stickers.push(info);
+            }
+        }
+        Ok(stickers)
+    }
+}
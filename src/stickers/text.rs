@@ -0,0 +1,71 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+pub fn extract_sticker_text(pdf_bytes: &[u8]) -> worker::Result<String> {
+    pdf_extract::extract_text_from_mem(pdf_bytes)
+        .map_err(|e| Error::from(format!("Couldn't parse pdf: {e:?}")))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StickerText {
+    pub vin: String,
+    pub text: String,
+    pub extracted_at: String,
+    /// Size of the pdf the text came from, unset for text cached before it was recorded.
+    pub pdf_size: Option<u32>,
+}
+
+impl StickerText {
+    /// Whether this is the text of the archived pdf, and not of one it has since replaced.
+    pub fn is_for(&self, pdf_size: u32) -> bool {
+        self.pdf_size == Some(pdf_size)
+    }
+}
+
+pub struct StickerTextCache {
+    d1: Database,
+}
+
+impl StickerTextCache {
+    pub fn new(d1: Database) -> Self {
+        StickerTextCache { d1 }
+    }
+
+    pub async fn get(&self, vin: &str) -> worker::Result<Option<StickerText>> {
+        let statement = self.d1.prepare("SELECT * FROM sticker_texts WHERE vin = ?");
+        let query = statement.bind(&[vin.into()])?;
+        query.first::<StickerText>(None).await
+    }
+
+    pub async fn put(&self, vin: &str, pdf_size: u32, text: &str) -> worker::Result<()> {
+        let statement = self.d1.prepare(
+            "INSERT INTO sticker_texts (vin, text, extracted_at, pdf_size) VALUES (?, ?, ?, ?)
+            ON CONFLICT (vin) DO UPDATE SET text = excluded.text, extracted_at = excluded.extracted_at, pdf_size = excluded.pdf_size",
+        );
+        let query = statement.bind(&[
+            vin.into(),
+            text.into(),
+            Utc::now().to_rfc3339().into(),
+            pdf_size.into(),
+        ])?;
+        query.run().await?;
+        Ok(())
+    }
+
+    /// Cached text for `vin`, extracting it from `pdf_bytes` and caching it on a miss or when the
+    /// cached text came from a different pdf.
+    pub async fn text_for(&self, vin: &str, pdf_bytes: &[u8]) -> worker::Result<String> {
+        let pdf_size = pdf_bytes.len() as u32;
+        if let Some(cached) = self
+            .get(vin)
+            .await?
+            .filter(|cached| cached.is_for(pdf_size))
+        {
+            return Ok(cached.text);
+        }
+        let text = extract_sticker_text(pdf_bytes)?;
+        self.put(vin, pdf_size, &text).await?;
+        Ok(text)
+    }
+}